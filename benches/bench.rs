@@ -1,5 +1,6 @@
 
 #![feature(hash_raw_entry)]
+#![feature(allocator_api)]
 
 #[macro_use]
 extern crate criterion;
@@ -31,6 +32,13 @@ use std::hash::Hash;
 use std::hash::Hasher;
 use std::hash::BuildHasher;
 use std::hash::BuildHasherDefault;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::alloc::{Alloc, AllocErr, Layout};
+use std::cell::{Cell, RefCell};
+use std::ptr::NonNull;
+use std::cmp;
 
 fn new_drop(b: &mut Bencher) {
 
@@ -71,6 +79,25 @@ fn find_existing_map(b: &mut Bencher) {
     });
 }
 
+fn find_existing_map_level(b: &mut Bencher, level: bench::simd::Level) {
+    let detected = bench::simd::level();
+    bench::simd::force(level);
+    find_existing_map(b);
+    bench::simd::force(detected);
+}
+
+fn find_existing_map_scalar(b: &mut Bencher) {
+    find_existing_map_level(b, bench::simd::Level::Scalar)
+}
+
+fn find_existing_map_sse41(b: &mut Bencher) {
+    find_existing_map_level(b, bench::simd::Level::Sse41)
+}
+
+fn find_existing_map_avx2(b: &mut Bencher) {
+    find_existing_map_level(b, bench::simd::Level::Avx2)
+}
+
 fn find_existing(b: &mut Bencher) {
     let mut m = bench::fx::FxHashMap::default();
 
@@ -101,6 +128,25 @@ fn find_nonexisting_map(b: &mut Bencher) {
     });
 }
 
+fn find_nonexisting_map_level(b: &mut Bencher, level: bench::simd::Level) {
+    let detected = bench::simd::level();
+    bench::simd::force(level);
+    find_nonexisting_map(b);
+    bench::simd::force(detected);
+}
+
+fn find_nonexisting_map_scalar(b: &mut Bencher) {
+    find_nonexisting_map_level(b, bench::simd::Level::Scalar)
+}
+
+fn find_nonexisting_map_sse41(b: &mut Bencher) {
+    find_nonexisting_map_level(b, bench::simd::Level::Sse41)
+}
+
+fn find_nonexisting_map_avx2(b: &mut Bencher) {
+    find_nonexisting_map_level(b, bench::simd::Level::Avx2)
+}
+
 fn find_nonexisting(b: &mut Bencher) {
 
     let mut m = bench::fx::FxHashMap::default();
@@ -201,6 +247,20 @@ fn syntax_syntex_hit_rate() {
     println!("large (>16 bytes) keys: {} of {}", large_16, m.len());
     println!("large (>32 bytes) keys: {} of {}", large_32, m.len());
     println!("large (>64 bytes) keys: {} of {}", large_64, m.len());
+
+    let mut set = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>>::new();
+    for s in strs {
+        set.intern(*s);
+    }
+    println!("set stats:\n{}", set.stats());
+    // The map only holds 8 byte keys, so it gets the hashes of the symbols
+    let mut map = Map::<u64, u64, BuildHasherDefault<bench::fx::FxHasher>>::with_capacity(m.len());
+    for (i, &k) in set.iter().enumerate() {
+        let mut hasher = FxHasher2::default();
+        k.hash(&mut hasher);
+        map.insert(hasher.finish(), i as u64);
+    }
+    println!("map stats:\n{}", map.stats());
 }
 
 fn syntax_syntex_symbols_str(b: &mut Bencher) {
@@ -635,6 +695,272 @@ fn symbols_indirect_intern_set(b: &mut Bencher) {
     });
 }
 
+fn symbols_intern_set(b: &mut Bencher) {
+    let strs = &SYMBOLS.1;
+
+    b.iter(|| {
+        let mut m = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>>::new();
+        for s in strs {
+            m.intern(*s);
+        }
+    });
+}
+
+fn symbols_intern_set_raw(b: &mut Bencher) {
+    #[inline(never)]
+    fn intern<'a>(map: &'a mut bench::Set<String, BuildHasherDefault<FxHasher2>>, string: &str) -> &'a str {
+        let hash = bench::set::make_hash(map.hasher(), string);
+        let entry = map.raw_entry_mut().from_key_hashed_nocheck(hash, string);
+
+        match entry {
+            bench::set::RawEntryMut::Occupied(e) => e.into_key(),
+            bench::set::RawEntryMut::Vacant(e) => {
+                // Only allocate a key for strings we haven't seen yet
+                e.insert_hashed_nocheck(hash, string.to_string())
+            }
+        }
+    }
+
+    let strs = &SYMBOLS.1;
+
+    b.iter(|| {
+        let mut m = bench::Set::new();
+        for s in strs {
+            intern(&mut m, s);
+        }
+    });
+}
+
+fn symbols_intern_set_level(b: &mut Bencher, level: bench::simd::Level) {
+    let strs = &SYMBOLS.1;
+    let detected = bench::simd::level();
+    bench::simd::force(level);
+
+    b.iter(|| {
+        let mut m = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>>::new();
+        for s in strs {
+            m.intern(*s);
+        }
+    });
+
+    bench::simd::force(detected);
+}
+
+fn symbols_intern_set_scalar(b: &mut Bencher) {
+    symbols_intern_set_level(b, bench::simd::Level::Scalar)
+}
+
+fn symbols_intern_set_sse2(b: &mut Bencher) {
+    symbols_intern_set_level(b, bench::simd::Level::Sse2)
+}
+
+fn symbols_intern_set_avx2(b: &mut Bencher) {
+    symbols_intern_set_level(b, bench::simd::Level::Avx2)
+}
+
+static EQ_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// A key which counts how often it's compared, so we can see how many calls
+/// to `eq` the hashes stored in the set don't filter out.
+#[derive(Hash, Copy, Clone, Debug)]
+struct CountEq<T>(T);
+
+impl<T: PartialEq> PartialEq for CountEq<T> {
+    fn eq(&self, other: &CountEq<T>) -> bool {
+        EQ_CALLS.fetch_add(1, Ordering::Relaxed);
+        self.0 == other.0
+    }
+}
+
+impl<T: Eq> Eq for CountEq<T> {}
+
+/// Returns the number of calls to `eq` which returned false and the number of lookups.
+fn symbols_false_eq<H: bench::set::HashWord>() -> (usize, usize) {
+    let strs = &SYMBOLS.1;
+    let mut m = bench::Set::<CountEq<&'static str>, BuildHasherDefault<FxHasher2>, H>::new();
+    EQ_CALLS.store(0, Ordering::Relaxed);
+    for s in strs {
+        m.intern(CountEq(*s));
+    }
+    // Interning a string we've seen before has a single call to `eq` which returns true
+    let hits = strs.len() - m.len();
+    (EQ_CALLS.load(Ordering::Relaxed) - hits, strs.len())
+}
+
+/// Like `symbols_false_eq`, but looks up keys missing from a large set.
+fn nonexisting_false_eq<H: bench::set::HashWord>() -> (usize, usize) {
+    let mut m = bench::Set::<CountEq<u64>, BuildHasherDefault<bench::fx::FxHasher>, H>::new();
+    for i in 0..(1u64 << 20) {
+        m.insert(CountEq(i));
+    }
+    EQ_CALLS.store(0, Ordering::Relaxed);
+    for i in (1u64 << 20)..(2u64 << 20) {
+        assert!(m.get(&CountEq(i)).is_none());
+    }
+    (EQ_CALLS.load(Ordering::Relaxed), 1 << 20)
+}
+
+fn hash64_false_eq_rate() {
+    fn print(name: &str, (calls, lookups): (usize, usize)) {
+        println!("{}: {} false eq calls in {} lookups ({:.6} per lookup)",
+            name, calls, lookups, calls as f64 / lookups as f64);
+    }
+    print("symbols, 32-bit hashes", symbols_false_eq::<u32>());
+    print("symbols, 64-bit hashes", symbols_false_eq::<u64>());
+    print("nonexisting, 32-bit hashes", nonexisting_false_eq::<u32>());
+    print("nonexisting, 64-bit hashes", nonexisting_false_eq::<u64>());
+}
+
+fn symbols_intern_set_hash<H: bench::set::HashWord>(b: &mut Bencher) {
+    let strs = &SYMBOLS.1;
+
+    b.iter(|| {
+        let mut m = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>, H>::new();
+        for s in strs {
+            m.intern(*s);
+        }
+    });
+}
+
+fn find_nonexisting_set_hash<H: bench::set::HashWord>(b: &mut Bencher) {
+    let mut m = bench::Set::<u64, BuildHasherDefault<bench::fx::FxHasher>, H>::new();
+
+    for i in 1..100100u64 {
+        m.insert(i);
+    }
+
+    b.iter(|| {
+        for i in 100100..200100 {
+            black_box(m.get(&i));
+        }
+    });
+}
+
+const INTERN_THREADS: usize = 4;
+
+/// Splits the symbols between `INTERN_THREADS` threads which call `intern` on each of them.
+fn intern_from_threads<T: Send + Sync + 'static>(set: Arc<T>, intern: fn(&T, &'static str)) {
+    let strs: &'static [&'static str] = &SYMBOLS.1;
+    let chunk = (strs.len() + INTERN_THREADS - 1) / INTERN_THREADS;
+    let threads: Vec<_> = strs.chunks(chunk).map(|strs| {
+        let set = set.clone();
+        thread::spawn(move || {
+            for s in strs {
+                intern(&set, *s);
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+fn symbols_intern_mutex_set(b: &mut Bencher) {
+    b.iter(|| {
+        let m = Arc::new(Mutex::new(bench::Set::<&'static str, BuildHasherDefault<FxHasher2>>::new()));
+        intern_from_threads(m, |m, s| {
+            m.lock().unwrap().intern(s);
+        });
+    });
+}
+
+fn symbols_intern_sharded(b: &mut Bencher) {
+    b.iter(|| {
+        let m = Arc::new(bench::ShardedSet::<&'static str, BuildHasherDefault<FxHasher2>>::new());
+        intern_from_threads(m, |m, s| {
+            m.intern(s);
+        });
+    });
+}
+
+const ARENA_CHUNK: usize = 64 * 1024;
+
+/// A bump allocator. `dealloc` does nothing, the memory is freed when the arena is dropped.
+struct BumpArena {
+    chunks: RefCell<Vec<Vec<u8>>>,
+    next: Cell<usize>,
+    end: Cell<usize>,
+}
+
+impl BumpArena {
+    fn new() -> BumpArena {
+        BumpArena {
+            chunks: RefCell::new(Vec::new()),
+            next: Cell::new(0),
+            end: Cell::new(0),
+        }
+    }
+}
+
+unsafe impl<'a> Alloc for &'a BumpArena {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let align = layout.align();
+        let mut start = (self.next.get() + align - 1) & !(align - 1);
+        if start + layout.size() > self.end.get() {
+            let mut chunk: Vec<u8> = Vec::with_capacity(cmp::max(ARENA_CHUNK, layout.size() + align));
+            let ptr = chunk.as_mut_ptr() as usize;
+            start = (ptr + align - 1) & !(align - 1);
+            self.end.set(ptr + chunk.capacity());
+            self.chunks.borrow_mut().push(chunk);
+        }
+        self.next.set(start + layout.size());
+        Ok(NonNull::new_unchecked(start as *mut u8))
+    }
+
+    unsafe fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {}
+}
+
+fn symbols_intern_set_arena(b: &mut Bencher) {
+    let strs = &SYMBOLS.1;
+
+    b.iter(|| {
+        let arena = BumpArena::new();
+        let mut m = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>, u32, &BumpArena>::new_in(&arena);
+        for s in strs {
+            m.intern(*s);
+        }
+    });
+}
+
+/// The policies compared on the symbol workload, named after their maximum
+/// load and growth factor.
+const POLICIES: &[(&str, usize, usize, u32)] = &[
+    ("10_13_x2", 10, 13, 1),
+    ("10_11_x2", 10, 11, 1),
+    ("7_8_x2", 7, 8, 1),
+    ("1_2_x2", 1, 2, 1),
+    ("10_13_x4", 10, 13, 2),
+    ("7_8_x4", 7, 8, 2),
+];
+
+fn symbols_intern_set_policy(b: &mut Bencher, policy: bench::Policy) {
+    let strs = &SYMBOLS.1;
+
+    b.iter(|| {
+        let mut m = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>>::new();
+        m.set_policy(policy);
+        for s in strs {
+            m.intern(*s);
+        }
+    });
+}
+
+/// Only looks up the symbols, so the time spent growing the table doesn't count.
+fn symbols_get_set_policy(b: &mut Bencher, policy: bench::Policy) {
+    let strs = &SYMBOLS.1;
+    let mut m = bench::Set::<&'static str, BuildHasherDefault<FxHasher2>>::new();
+    m.set_policy(policy);
+    for s in strs {
+        m.intern(*s);
+    }
+
+    b.iter(|| {
+        for s in strs {
+            black_box(m.get(s));
+        }
+    });
+}
+
 fn symbols_indirect_set_intern_cap(b: &mut Bencher) {
     let strs = &SYMBOLS.1;
 
@@ -687,6 +1013,36 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("symbols_indirect_intern_raw", symbols_indirect_intern_raw);
     c.bench_function("symbols_indirect_intern", symbols_indirect_intern);
     c.bench_function("symbols_indirect_intern_set", symbols_indirect_intern_set);
+    c.bench_function("symbols_intern_set", symbols_intern_set);
+    c.bench_function("symbols_intern_set_raw", symbols_intern_set_raw);
+    c.bench_function("symbols_intern_set_scalar", symbols_intern_set_scalar);
+    c.bench_function("symbols_intern_set_sse2", symbols_intern_set_sse2);
+    if bench::simd::supported(bench::simd::Level::Avx2) {
+        c.bench_function("symbols_intern_set_avx2", symbols_intern_set_avx2);
+    }
+    c.bench_function("find_existing_map_scalar", find_existing_map_scalar);
+    c.bench_function("find_nonexisting_map_scalar", find_nonexisting_map_scalar);
+    if bench::simd::supported(bench::simd::Level::Sse41) {
+        c.bench_function("find_existing_map_sse41", find_existing_map_sse41);
+        c.bench_function("find_nonexisting_map_sse41", find_nonexisting_map_sse41);
+    }
+    if bench::simd::supported(bench::simd::Level::Avx2) {
+        c.bench_function("find_existing_map_avx2", find_existing_map_avx2);
+        c.bench_function("find_nonexisting_map_avx2", find_nonexisting_map_avx2);
+    }
+    hash64_false_eq_rate();
+    c.bench_function("symbols_intern_set_hash32", symbols_intern_set_hash::<u32>);
+    c.bench_function("symbols_intern_set_hash64", symbols_intern_set_hash::<u64>);
+    c.bench_function("find_nonexisting_set_hash32", find_nonexisting_set_hash::<u32>);
+    c.bench_function("find_nonexisting_set_hash64", find_nonexisting_set_hash::<u64>);
+    c.bench_function("symbols_intern_mutex_set", symbols_intern_mutex_set);
+    c.bench_function("symbols_intern_sharded", symbols_intern_sharded);
+    c.bench_function("symbols_intern_set_arena", symbols_intern_set_arena);
+    for &(name, num, den, shift) in POLICIES {
+        let policy = bench::Policy::new(num, den, shift);
+        c.bench_function(&format!("symbols_intern_set_policy_{}", name), move |b| symbols_intern_set_policy(b, policy));
+        c.bench_function(&format!("symbols_get_set_policy_{}", name), move |b| symbols_get_set_policy(b, policy));
+    }
     //c.bench_function("symbols_indirect_set_intern_simple", symbols_indirect_set_intern_simple);
     /*c.bench_function("symbols_indirect_cap", symbols_indirect_cap);
     c.bench_function("symbols_indirect_hashbrown_cap", symbols_indirect_hashbrown_cap);
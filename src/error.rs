@@ -0,0 +1,41 @@
+use std::alloc::AllocErr;
+use std::fmt;
+
+/// The reason a table couldn't get the memory it needed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CollectionAllocErr {
+    /// The number of entries or the size of the table doesn't fit in a `usize`.
+    CapacityOverflow,
+    /// The allocator returned an error.
+    AllocErr,
+}
+
+impl From<AllocErr> for CollectionAllocErr {
+    fn from(_: AllocErr) -> Self {
+        CollectionAllocErr::AllocErr
+    }
+}
+
+impl fmt::Display for CollectionAllocErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CollectionAllocErr::CapacityOverflow => f.write_str("capacity overflow"),
+            CollectionAllocErr::AllocErr => f.write_str("memory allocation failed"),
+        }
+    }
+}
+
+/// Used by the infallible methods to panic where the `try_` methods return an error.
+#[inline]
+pub fn unwrap<T>(result: Result<T, CollectionAllocErr>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => fail(err),
+    }
+}
+
+#[inline(never)]
+#[cold]
+fn fail(err: CollectionAllocErr) -> ! {
+    panic!("{}", err)
+}
@@ -18,8 +18,18 @@ pub mod set;
 pub mod map;
 
 pub use set::Set;
+pub use sharded::ShardedSet;
+pub use error::CollectionAllocErr;
+pub use stats::Stats;
+pub use policy::Policy;
 
 pub mod fx;
+pub mod simd;
+pub mod sharded;
+pub mod error;
+pub mod stats;
+pub mod policy;
+mod table;
 
 pub type HashMap<K, V> = fx::FxHashMap<K, V>;
 
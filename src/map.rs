@@ -3,12 +3,18 @@ use std::marker::PhantomData;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::hash::BuildHasher;
-use std::mem::{size_of, align_of};
-use std::ptr::{Unique, NonNull};
+use std::mem::{self, size_of, align_of};
+use std::ptr;
 use std::alloc::{Global, Alloc};
 use std::collections::hash_map::RandomState;
-use std::borrow::Borrow;
-use std;
+use std::collections::HashMap;
+use std::iter::FromIterator;
+use std::fmt::{self, Debug};
+use error::{self, CollectionAllocErr};
+use stats::Stats;
+use policy::Policy;
+use table::{self, Group as TableGroup};
+use simd;
 
 /*
 const ENTRIES_PER_GROUP: usize = 5;
@@ -23,64 +29,117 @@ pub struct Group {
 */
 const ENTRIES_PER_GROUP: usize = 4;
 
-// Make Hashtable generic over the Group, so we can have one Group for 32-bit keys, 64-bit values etc.
-
 // Group has a u32 unused. Store metadata there?
 // Store a bool if the group is full, so we don't need to find that out
+/// Keys and values are stored as their bits. Empty slots hold the bits of `K::sentinel()`.
 #[repr(align(64), C)]
-pub struct Group {
+pub struct Group<K, V> {
     keys: [u64; ENTRIES_PER_GROUP],
     values: [u64; ENTRIES_PER_GROUP],
+    marker: PhantomData<(K, V)>,
 }
 
-impl Group {
+impl<K: Sentinel, V> Group<K, V> {
+    /// The bits of the keys in empty slots.
     #[inline(always)]
-    fn search_for_empty(&self, sentinel: u64) -> Option<usize> {
-        for i in 0..ENTRIES_PER_GROUP {
-            if unsafe { *self.keys.get_unchecked(i) == sentinel } {
-                return Some(i)
-            }
-        }
-        None
+    pub fn sentinel() -> u64 {
+        bits(&K::sentinel())
     }
-/*
+
+    /// The bits of the keys, for processing a whole group at once. Slots whose key
+    /// is `sentinel()` are empty.
     #[inline(always)]
-    fn search_with(&self, key: u64, sentinel: u64) -> Option<(usize, bool)> {
-        // This unrolls
-        for i in 0..ENTRIES_PER_GROUP {
-            let k = unsafe { *self.keys.get_unchecked(i) };
-            if k == key {
-                return Some((i, false))
-            }
-        }
-        self.search_for_empty(sentinel).map(|i| (i, true))
+    pub fn keys(&self) -> &[u64; ENTRIES_PER_GROUP] {
+        &self.keys
     }
-*/
+
+    /// The bits of the values, in the same order as `keys`. Values in empty slots
+    /// are left over from earlier entries or uninitialized.
     #[inline(always)]
-    fn search_with(&self, key: u64, sentinel: u64) -> Option<(usize, bool)> {
-        use std::arch::x86_64::*;
+    pub fn values(&self) -> &[u64; ENTRIES_PER_GROUP] {
+        &self.values
+    }
+
+    #[inline(always)]
+    fn entry(&self, pos: usize) -> (u64, u64) {
         unsafe {
-        let keys = _mm256_load_si256(&self.keys as *const _ as *const _);
-        let key = _mm256_set1_epi64x(key as i64);
-        let eq =  _mm256_cmpeq_epi64(keys, key);
-        let mask = _mm256_movemask_epi8(eq) as u32;
-        let idx = std::intrinsics::cttz(mask) as usize;
-        if idx != 32 {
-            Some((idx >> 3, true))
+            (*self.keys.get_unchecked(pos), *self.values.get_unchecked(pos))
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn key(&self, pos: usize) -> &K {
+        &*(self.keys.get_unchecked(pos) as *const u64 as *const K)
+    }
+
+    #[inline(always)]
+    unsafe fn value(&self, pos: usize) -> *mut V {
+        self.values.get_unchecked(pos) as *const u64 as *mut V
+    }
+
+    #[inline(always)]
+    fn is_full(&self) -> bool {
+        self.search_for_empty().is_none()
+    }
+
+    /// Looks for the key with the bits `key`, comparing the keys with `M`. Returns
+    /// `(pos, false)` if it's there and `(pos, true)` with an empty slot if it isn't,
+    /// or `None` if the group is full. Only call this from functions with the target
+    /// features `M` needs enabled.
+    #[inline(always)]
+    unsafe fn search_matching<M: Matcher>(&self, key: u64) -> Option<(usize, bool)> {
+        let mask = M::matches(self, key);
+        if mask != 0 {
+            return Some((mask.trailing_zeros() as usize, false));
+        }
+        let mask = M::matches(self, Self::sentinel());
+        if mask != 0 {
+            Some((mask.trailing_zeros() as usize, true))
         } else {
-            let sentinel = _mm256_set1_epi64x(sentinel as i64);
-            let eq =  _mm256_cmpeq_epi64(keys, sentinel);
-            let mask = _mm256_movemask_epi8(eq) as u32;
-            let idx = std::intrinsics::cttz(mask) as usize;
-            if idx != 32 {
-                Some((idx >> 3, false))
-            } else {
-                None
-            }
+            None
         }
+    }
+
+    /// Empties the slot at `pos` and returns the bits of its key and value.
+    #[inline(always)]
+    fn take(&mut self, pos: usize) -> (u64, u64) {
+        let entry = self.entry(pos);
+        unsafe {
+            *self.keys.get_unchecked_mut(pos) = Self::sentinel();
         }
+        entry
+    }
+}
+
+impl<K: Sentinel, V> TableGroup for Group<K, V> {
+    type Entry = u64;
+    type Tag = u64;
+
+    #[inline(always)]
+    fn entries() -> usize {
+        ENTRIES_PER_GROUP
+    }
+
+    #[inline(always)]
+    fn stride() -> usize {
+        size_of::<Self>()
+    }
+
+    #[inline(always)]
+    fn clear(&mut self) {
+        self.keys = [Self::sentinel(); ENTRIES_PER_GROUP];
     }
 
+    #[inline(always)]
+    fn search_for_empty(&self) -> Option<usize> {
+        let sentinel = Self::sentinel();
+        for i in 0..ENTRIES_PER_GROUP {
+            if unsafe { *self.keys.get_unchecked(i) == sentinel } {
+                return Some(i)
+            }
+        }
+        None
+    }
     #[inline(always)]
     fn set(&mut self, pos: usize, key: u64, value: u64) {
         unsafe {
@@ -90,177 +149,227 @@ impl Group {
     }
 
     #[inline(always)]
-    fn iter<F: FnMut(u64, u64)>(&self, sentinel: u64, f: &mut F) {
+    fn iter<F: FnMut(usize)>(&self, mut f: F) {
+        let sentinel = Self::sentinel();
         for i in 0..ENTRIES_PER_GROUP {
-            unsafe {
-                let k = *self.keys.get_unchecked(i);
-                if k != sentinel {
-                    f(k, *self.values.get_unchecked(i));
-                }
+            if unsafe { *self.keys.get_unchecked(i) } != sentinel {
+                f(i);
             }
         }
     }
 }
 
-pub struct Table {
-    group_mask: usize,
-    size: usize,
-    capacity: usize,
-    groups: Unique<Group>,
+type Table<K, V, A> = table::Table<Group<K, V>, A>;
+
+type RawEntry<K, V> = table::RawEntry<Group<K, V>>;
+
+/// The bits of an 8 byte key or value, which is how groups store them.
+#[inline(always)]
+fn bits<T>(value: &T) -> u64 {
+    debug_assert!(size_of::<T>() == 8);
+    unsafe {
+        *(value as *const T as *const u64)
+    }
+}
+
+/// Turns bits stored by a group back into the key or value they came from.
+#[inline(always)]
+unsafe fn from_bits<T>(bits: u64) -> T {
+    ptr::read(&bits as *const u64 as *const T)
 }
 
-#[derive(Debug)]
-pub struct RawEntry {
-    group: *mut Group,
-    pos: usize,
-    empty: bool
-}
-
-impl Table {
-    /// Does not initialize the buckets. The caller should ensure they,
-    /// at the very least, set every hash to EMPTY_BUCKET.
-    /// Returns an error if it cannot allocate or capacity overflows.
-    unsafe fn new_uninitialized(group_count: usize, sentinel: u64) -> Table {
-        assert!(size_of::<Group>() == 64);
-        let groups: NonNull<Group> = Global.alloc_array(group_count).unwrap();
-        let capacity2 = group_count * ENTRIES_PER_GROUP;
-        let capacity1 = capacity2 - 1;
-        //let capacity = (capacity1 * 10 + 10 - 1) / 11;
-        let capacity = (capacity1 * 10 + 10 - 1) / 13;
-        //println!("capacity1 {} capacity {}", capacity1, capacity);
-        assert!(capacity < capacity2);
+/// Finds the slots in a group whose key is equal to `key` and returns them as a bitmask.
+trait Matcher {
+    unsafe fn matches<K, V>(group: &Group<K, V>, key: u64) -> u32;
+}
 
-        for i in 0..group_count {
-            let group = unsafe {
-                &mut (*groups.as_ptr().offset(i as isize))
-            };
-            group.keys = [sentinel; ENTRIES_PER_GROUP];
-        }
+struct Scalar;
 
-        Table {
-            group_mask: group_count.wrapping_sub(1),
-            size: 0,
-            capacity,
-            groups: Unique::new_unchecked(groups.as_ptr()),
+impl Matcher for Scalar {
+    #[inline(always)]
+    unsafe fn matches<K, V>(group: &Group<K, V>, key: u64) -> u32 {
+        let mut mask = 0;
+        // This unrolls
+        for i in 0..ENTRIES_PER_GROUP {
+            if *group.keys.get_unchecked(i) == key {
+                mask |= 1 << i;
+            }
         }
+        mask
     }
+}
 
-    fn search_for_empty(&self, hash: u64, sentinel: u64) -> RawEntry {
-        let group_idx = hash as usize;
-        let mask = self.group_mask;
-        let mut group_idx = group_idx & mask;
+#[cfg(target_arch = "x86_64")]
+struct Sse41;
 
-        loop {
-            //println!("checking group {}", group_idx);
-            let group_ptr = unsafe {
-                self.groups.as_ptr().offset(group_idx as isize)
-            };
-            let group = unsafe {
-                &(*group_ptr)
-            };
-            match unsafe { group.search_for_empty(sentinel) } {
-                Some(pos) => return RawEntry {
-                    group: group_ptr,
-                    pos,
-                    empty: true,
-                },
-                None => (),
-            }
-            group_idx = (group_idx + 1) & mask;
-        }
+#[cfg(target_arch = "x86_64")]
+impl Matcher for Sse41 {
+    /// Only call this from functions with the `sse4.1` target feature enabled.
+    #[inline(always)]
+    unsafe fn matches<K, V>(group: &Group<K, V>, key: u64) -> u32 {
+        use std::arch::x86_64::*;
+        // The keys are at the start of the group, so they are aligned
+        let keys = &group.keys as *const _ as *const __m128i;
+        let key = _mm_set1_epi64x(key as i64);
+        let low = _mm_cmpeq_epi64(_mm_load_si128(keys), key);
+        let high = _mm_cmpeq_epi64(_mm_load_si128(keys.offset(1)), key);
+        (_mm_movemask_pd(_mm_castsi128_pd(low)) | _mm_movemask_pd(_mm_castsi128_pd(high)) << 2) as u32
     }
+}
 
-    fn search_with(&self, hash: u64, sentinel: u64) -> RawEntry {
-        let group_idx = hash as usize;
-        let mask = self.group_mask;
-        let mut group_idx = group_idx & mask;
+#[cfg(target_arch = "x86_64")]
+struct Avx2;
 
-        loop {
-            //println!("checking group {}", group_idx);
-            let group_ptr = unsafe {
-                self.groups.as_ptr().offset(group_idx as isize)
-            };
-            let group = unsafe {
-                &(*group_ptr)
-            };
-            let r = unsafe { group.search_with(hash, sentinel) } ;
-            //let r2 = unsafe { group.search_with2(&mut eq, hash as u32) } ;
-            //assert_eq!(r, r2);
-            //println!("search_with {}: {:?}", group_idx, r);
-            match r {
-                Some((pos, empty)) => return RawEntry {
-                    group: group_ptr,
-                    pos,
-                    empty,
-                },
-                None => (),
-            }
-            group_idx = (group_idx + 1) & mask;
+#[cfg(target_arch = "x86_64")]
+impl Matcher for Avx2 {
+    /// Only call this from functions with the `avx2` target feature enabled.
+    #[inline(always)]
+    unsafe fn matches<K, V>(group: &Group<K, V>, key: u64) -> u32 {
+        use std::arch::x86_64::*;
+        let keys = _mm256_load_si256(&group.keys as *const _ as *const __m256i);
+        let eq = _mm256_cmpeq_epi64(keys, _mm256_set1_epi64x(key as i64));
+        _mm256_movemask_pd(_mm256_castsi256_pd(eq)) as u32
+    }
+}
+
+impl<K: Sentinel, V, A: Alloc> Table<K, V, A> {
+    /// Finds the entry with the key bits `key`, or else the slot to insert it into,
+    /// comparing keys with the widest SIMD instructions available.
+    #[inline(always)]
+    fn lookup(&self, hash: u64, key: u64) -> RawEntry<K, V> {
+        match simd::level() {
+            #[cfg(target_arch = "x86_64")]
+            simd::Level::Avx2 => unsafe { self.lookup_avx2(hash, key) },
+            #[cfg(target_arch = "x86_64")]
+            simd::Level::Sse41 => unsafe { self.lookup_sse41(hash, key) },
+            _ => unsafe { self.lookup_with::<Scalar>(hash, key) },
         }
     }
 
-    fn iter<F: FnMut(u64, u64)>(&self, sentinel: u64, mut f: F) {
-        for i in 0..(self.group_mask + 1) {
-            let group = unsafe {
-                &(*self.groups.as_ptr().offset(i as isize))
-            };
-            group.iter(sentinel, &mut f);
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn lookup_avx2(&self, hash: u64, key: u64) -> RawEntry<K, V> {
+        self.lookup_with::<Avx2>(hash, key)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn lookup_sse41(&self, hash: u64, key: u64) -> RawEntry<K, V> {
+        self.lookup_with::<Sse41>(hash, key)
+    }
+
+    /// Only call this from functions with the target features `M` needs enabled.
+    #[inline(always)]
+    unsafe fn lookup_with<M: Matcher>(&self, hash: u64, key: u64) -> RawEntry<K, V> {
+        self.probe(hash, |group| group.search_matching::<M>(key))
+    }
+
+    fn raw_iter(&self) -> RawIter<K, V> {
+        RawIter {
+            group: self.group(0),
+            pos: 0,
+            remaining: self.size,
         }
     }
 }
 
-impl Drop for Table {
-    fn drop(&mut self) {
-        unsafe {
-            Global.dealloc_array(
-                NonNull::new_unchecked(self.groups.as_ptr()),
-                self.group_mask + 1
-            ).unwrap();
+/// Walks the groups of a table, yielding the occupied slots. Stops once `remaining`
+/// entries have been seen, so it doesn't have to look at the groups after the last one.
+struct RawIter<K, V> {
+    group: *mut Group<K, V>,
+    pos: usize,
+    remaining: usize,
+}
+
+impl<K: Sentinel, V> RawIter<K, V> {
+    #[inline(always)]
+    unsafe fn next(&mut self) -> Option<(*mut Group<K, V>, usize)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if self.pos < ENTRIES_PER_GROUP {
+                let pos = self.pos;
+                self.pos += 1;
+                if (*self.group).entry(pos).0 != Group::<K, V>::sentinel() {
+                    self.remaining -= 1;
+                    return Some((self.group, pos));
+                }
+                continue;
+            }
+            self.group = self.group.offset(1);
+            self.pos = 0;
         }
     }
+
+    /// Like `next`, but also moves the entry out of the table, leaving an empty slot.
+    /// The probe sequences through the slot are broken afterwards, so this is only
+    /// used when the whole table is being emptied.
+    #[inline(always)]
+    unsafe fn take(&mut self) -> Option<(K, V)> {
+        self.next().map(|(group, pos)| {
+            let (k, v) = (*group).take(pos);
+            (from_bits(k), from_bits(v))
+        })
+    }
 }
 
-pub trait Sentinel {
+/// Keys a `Map` can hold, with the key whose bits mark empty slots.
+///
+/// This is unsafe to implement because the map compares keys by their bits and
+/// never calls `eq`. Keys have to be 8 bytes without padding, and two keys have to
+/// be equal exactly when their bits are.
+pub unsafe trait Sentinel: Eq + Copy {
     fn sentinel() -> Self;
 }
 
-impl Sentinel for u64 {
+unsafe impl Sentinel for u64 {
     fn sentinel() -> Self {
         -1i64 as u64
     }
 }
 
-pub struct Map<K: Eq + Hash + Copy + Sentinel, V, S: BuildHasher = RandomState> {
+/// A map with 8 byte keys and values. The table is allocated with `A`.
+///
+/// Keys are found by comparing their bits, the hash only picks the group a lookup
+/// starts in. So `K`'s `Eq` has to agree with comparing the bits, which implementing
+/// `Sentinel` promises.
+///
+/// Any key can be stored. Slots holding `K::sentinel()` are the empty ones, so the
+/// entry for that key is kept outside the table.
+pub struct Map<K: Hash + Sentinel, V, S: BuildHasher = RandomState, A: Alloc + Clone = Global> {
     hash_builder: S,
-    table: Table,
+    table: Table<K, V, A>,
+    sentinel: Option<(K, V)>,
+    policy: Policy,
     marker: PhantomData<(K, V)>,
 }
 
-impl<K: Eq + Hash + Copy + Sentinel, V, S: Default + BuildHasher> Map<K, V, S> {
+impl<K: Hash + Sentinel, V, S: Default + BuildHasher, A: Alloc + Clone + Default> Map<K, V, S, A> {
     pub fn new() -> Self {
-        assert!(size_of::<K>() == 8);
-        assert!(align_of::<K>() == 8);
-        assert!(size_of::<V>() == 8);
-        assert!(align_of::<V>() == 8);
-        Map {
-            hash_builder: S::default(),
-            table: unsafe { Table::new_uninitialized(2, Self::sentinel()) },
-            marker: PhantomData,
-        }
+        Self::with_hasher(S::default())
     }
 
     pub fn with_capacity(s: usize) -> Self {
-        let groups = (s * ENTRIES_PER_GROUP + ENTRIES_PER_GROUP - 1) / ENTRIES_PER_GROUP;
-        let groups = groups.checked_next_power_of_two().unwrap();
+        error::unwrap(Self::try_with_capacity(s))
+    }
+
+    /// Like `with_capacity`, but returns an error instead of panicking if the
+    /// table can't be allocated.
+    pub fn try_with_capacity(s: usize) -> Result<Self, CollectionAllocErr> {
         assert!(size_of::<K>() == 8);
         assert!(align_of::<K>() == 8);
         assert!(size_of::<V>() == 8);
         assert!(align_of::<V>() == 8);
-        Map {
+        let policy = Policy::default();
+        let groups = Table::<K, V, A>::group_count_for(s, &policy)?;
+        Ok(Map {
             hash_builder: S::default(),
-            table: unsafe { Table::new_uninitialized(groups, Self::sentinel()) },
+            table: Table::new(groups, A::default(), &policy)?,
+            sentinel: None,
+            policy,
             marker: PhantomData,
-        }
+        })
     }
 }
 
@@ -273,83 +382,1367 @@ pub fn make_hash<T: ?Sized, S>(hash_state: &S, t: &T) -> u64
     state.finish()
 }
 
-impl<K: Eq + Hash + Copy + Sentinel, V, S: BuildHasher> Map<K, V, S> {
-    fn sentinel() -> u64 {
-        unsafe {
-            *(&K::sentinel() as *const _ as *const u64)
+impl<K: Hash + Sentinel, V, S: Default + BuildHasher, A: Alloc + Clone> Map<K, V, S, A> {
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_hasher_in(S::default(), alloc)
+    }
+}
+
+impl<K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone + Default> Map<K, V, S, A> {
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_hasher_in(hash_builder, A::default())
+    }
+}
+
+impl<K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> Map<K, V, S, A> {
+    /// Creates a map which allocates its table with `alloc`.
+    pub fn with_hasher_in(hash_builder: S, alloc: A) -> Self {
+        assert!(size_of::<K>() == 8);
+        assert!(align_of::<K>() == 8);
+        assert!(size_of::<V>() == 8);
+        assert!(align_of::<V>() == 8);
+        let policy = Policy::default();
+        Map {
+            hash_builder,
+            table: error::unwrap(Table::new(2, alloc, &policy)),
+            sentinel: None,
+            policy,
+            marker: PhantomData,
         }
     }
 
+    /// Moves the entries into a new table with `1 << growth_shift` times as many groups.
     #[inline(never)]
     #[cold]
-    fn expand(&mut self) {
-        let mut new_table = unsafe {
-            Table::new_uninitialized((self.table.group_mask + 1) << 1, Self::sentinel())
-        };
-        // Expand the table in place and move only the entries whose mask change
-        // We need to move entries within a group in that case, might not be a win
+    fn try_expand(&mut self) -> Result<(), CollectionAllocErr> {
+        let group_count = (self.table.group_mask + 1).checked_mul(1 << self.policy.growth_shift())
+            .ok_or(CollectionAllocErr::CapacityOverflow)?;
+        let mut new_table = Table::new(group_count, self.table.alloc.clone(), &self.policy)?;
         new_table.size = self.table.size;
-        //println!("expanding to {}", (self.table.group_mask + 1) * ENTRIES_PER_GROUP);
-        self.table.iter(Self::sentinel(), |k, v| {
-            let key = &k as *const _ as *const K;
-            //println!("moving {:?} with hash {}", unsafe { &*k }, h);
-            let h = make_hash(&self.hash_builder, &key);
-            let spot = new_table.search_for_empty(h, Self::sentinel());
+        // The keys are all different, so each goes into the first empty slot on its probe sequence
+        let hash_builder = &self.hash_builder;
+        self.table.iter(|group, pos| {
+            let (k, v) = group.entry(pos);
+            let h = make_hash(hash_builder, unsafe { group.key(pos) });
+            let spot = new_table.search_for_empty(h);
             unsafe {
-                (*spot.group).set(spot.pos, h, v);
+                (*spot.group).set(spot.pos, k, v);
             }
-            /*let spot = new_table.search_with::<K, _>(|key| unsafe {key == &*k}, h as u64);
-            if !spot.empty {
-                println!("NO FOUND {:?} with hash {}", unsafe { &*k }, h);
-            } else {
-                println!("found {:?} with hash {} at {:?}", unsafe { &*k }, h, spot);
-            }*/
         });
+        // The values now belong to the new table, so dropping the old one mustn't drop them
         self.table = new_table;
+        Ok(())
+    }
+
+    /// Grows the table until `additional` more entries fit without growing again.
+    pub fn reserve(&mut self, additional: usize) {
+        error::unwrap(self.try_reserve(additional))
+    }
+
+    /// Makes room for `additional` more entries. The map is left as it was if this fails.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), CollectionAllocErr> {
+        let needed = self.table.size.checked_add(additional).ok_or(CollectionAllocErr::CapacityOverflow)?;
+        while needed > self.table.capacity {
+            self.try_expand()?;
+        }
+        self.validate_change();
+        Ok(())
+    }
+
+    /// Changes when the table grows and by how much. If the map holds more entries
+    /// than the table allows under the new policy, it grows right away.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.table.capacity = Table::<K, V, A>::capacity(self.table.group_mask + 1, &policy);
+        self.reserve(0);
     }
 
     #[inline(always)]
-    fn incr(&mut self) {
+    fn try_incr(&mut self) -> Result<(), CollectionAllocErr> {
         if self.table.size + 1 > self.table.capacity {
-            self.expand()
+            self.try_expand()?;
         }
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
-        self.table.size
+        self.table.size + self.sentinel.is_some() as usize
+    }
+
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter {
+            sentinel: self.sentinel.as_ref(),
+            raw: self.table.raw_iter(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<K, V> {
+        IterMut {
+            sentinel: self.sentinel.as_mut(),
+            raw: self.table.raw_iter(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn keys(&self) -> Keys<K, V> {
+        Keys {
+            iter: self.iter(),
+        }
+    }
+
+    pub fn values(&self) -> Values<K, V> {
+        Values {
+            iter: self.iter(),
+        }
+    }
+
+    /// Removes all entries from the map, returning them in an iterator.
+    /// The map is empty once the iterator is dropped, even if it wasn't consumed.
+    pub fn drain(&mut self) -> Drain<K, V, A> {
+        // The table always has an empty slot, so some group isn't full
+        let group_idx = (0..(self.table.group_mask + 1)).find(|&i| unsafe {
+            !(*self.table.group(i)).is_full()
+        }).unwrap();
+        Drain {
+            sentinel: &mut self.sentinel,
+            table: &mut self.table,
+            group_idx,
+            entries: [(0, 0); ENTRIES_PER_GROUP],
+            taken: 0,
+        }
+    }
+
+    /// Visits the groups of the table in order, empty ones included, so their keys and
+    /// values can be processed in bulk. See `Group::keys` and `Group::values`.
+    /// The entry for `K::sentinel()` isn't in any group.
+    pub fn groups(&self) -> Groups<K, V> {
+        Groups {
+            group: self.table.group(0),
+            remaining: self.table.group_mask + 1,
+            marker: PhantomData,
+        }
+    }
+
+    /// Checks that lookups find every key and that the size counts them, describing
+    /// the first problem found. Slots holding the sentinel are the empty ones, so the
+    /// size has to match the slots which don't, and only the entry kept outside the
+    /// table may have the sentinel as its key. This is meant for finding bugs in `Map`
+    /// itself. With the `validate` feature it runs after every change.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some((ref k, _)) = self.sentinel {
+            if !Self::is_sentinel(k) {
+                return Err(format!("the entry kept outside the table has the key {:#x}, \
+                                    which isn't the sentinel", bits(k)));
+            }
+        }
+        let slots = (self.table.group_mask + 1) * ENTRIES_PER_GROUP;
+        let sentinels = (0..(self.table.group_mask + 1)).map(|i| {
+            let group = unsafe { &*self.table.group(i) };
+            group.keys().iter().filter(|&&key| key == Group::<K, V>::sentinel()).count()
+        }).sum::<usize>();
+        if slots - sentinels != self.table.size {
+            return Err(format!("the size is {}, but {} of the {} slots don't hold the sentinel",
+                               self.table.size, slots - sentinels, slots));
+        }
+        self.table.validate(|group, pos| {
+            make_hash(&self.hash_builder, unsafe { group.key(pos) })
+        })
+    }
+
+    /// Panics if `validate` fails, with the `validate` feature.
+    #[inline(always)]
+    fn validate_change(&self) {
+        if cfg!(feature = "validate") {
+            if let Err(err) = self.validate() {
+                panic!("invalid map: {}", err);
+            }
+        }
+    }
+
+    /// Describes how the entries are spread over the table, see `Stats`.
+    /// Groups compare whole keys instead of stored hashes, so there are no tag collisions.
+    /// The entry for `K::sentinel()` isn't in the table, so it isn't counted.
+    pub fn stats(&self) -> Stats {
+        let group_count = self.table.group_mask + 1;
+        let mut stats = Stats::new(ENTRIES_PER_GROUP);
+        for i in 0..group_count {
+            let group = unsafe {
+                &(*self.table.group(i))
+            };
+            let mut size = 0;
+            group.iter(|pos| {
+                let key = unsafe { group.key(pos) };
+                let home = make_hash(&self.hash_builder, key) as usize & self.table.group_mask;
+                stats.add_probe(i.wrapping_sub(home) & self.table.group_mask);
+                size += 1;
+            });
+            stats.group_sizes[size] += 1;
+        }
+        stats.len = self.table.size;
+        stats.capacity = self.table.capacity;
+        stats.groups = group_count;
+        stats.memory = group_count * Group::<K, V>::stride();
+        stats.finish();
+        stats
+    }
+
+    /// Returns true if `k` has to be kept outside the table, as it marks empty slots.
+    #[inline(always)]
+    fn is_sentinel(k: &K) -> bool {
+        bits(k) == Group::<K, V>::sentinel()
     }
 
+    /// Finds the slot holding `k`, or else the slot to insert it into.
+    /// `k` must not be the sentinel.
+    #[inline(always)]
+    fn search(&self, k: &K) -> RawEntry<K, V> {
+        let hash = make_hash(&self.hash_builder, k);
+        self.table.lookup(hash, bits(k))
+    }
+
+    /// Inserts `v` for `k`, returning the value `k` had before, if any.
     #[inline(never)]
-    pub fn insert(&mut self, k: K, v: V) {
-        self.incr();
-        assert!(k != K::sentinel());
-        let hash = make_hash(&self.hash_builder, &k);
-        let spot = self.table.search_with(hash, Self::sentinel());
-        if spot.empty {
-            self.table.size += 1;
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        error::unwrap(self.try_insert(k, v))
+    }
+
+    /// Like `insert`, but returns an error instead of panicking if the table
+    /// has to grow and can't. The map doesn't change in that case.
+    #[inline(always)]
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<Option<V>, CollectionAllocErr> {
+        if Self::is_sentinel(&k) {
+            return Ok(mem::replace(&mut self.sentinel, Some((k, v))).map(|(_, v)| v));
         }
-        //println!("inserting {:?} with hash {} at {:?}", unsafe { &k }, hash as u32, spot);
+        self.try_incr()?;
+        let spot = self.search(&k);
+        let old = if spot.empty {
+            self.table.size += 1;
+            None
+        } else {
+            unsafe {
+                Some(ptr::read((*spot.group).value(spot.pos)))
+            }
+        };
         unsafe {
-            (*spot.group).set(spot.pos, *(&k as *const _ as *const u64), *(&v as *const _ as *const u64));
+            (*spot.group).set(spot.pos, bits(&k), bits(&v));
         }
+        mem::forget(v);
+        self.validate_change();
+        Ok(old)
     }
 
     pub fn contains_key(&self, k: &K) -> bool {
-        self.get(k).is_some()
+        if Self::is_sentinel(k) {
+            return self.sentinel.is_some();
+        }
+        !self.search(k).empty
     }
 
-    pub fn get<Q: ?Sized>(&self, value: &Q) -> Option<&K>
-        where K: Borrow<Q>,
-              Q: Hash + Eq
-    {
-        let hash = make_hash(&self.hash_builder, value);
-        let spot = self.table.search_with(hash, Self::sentinel());
+    /// Removes `k` from the map, returning its value if it was there.
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        if Self::is_sentinel(k) {
+            return self.sentinel.take().map(|(_, v)| v);
+        }
+        let spot = self.search(k);
+        if spot.empty {
+            return None;
+        }
+        let value = unsafe { self.remove_at(spot.group, spot.pos) };
+        self.validate_change();
+        Some(value)
+    }
+
+    /// Removes the entry at `group`/`pos` and returns its value.
+    ///
+    /// Lookups stop at the first group with an empty slot, so emptying a slot in a full
+    /// group would hide entries which overflowed past it. We fix that up by moving such
+    /// an entry back into the hole, which opens a hole in its old group, and repeat until
+    /// we empty a slot in a group which wasn't full. We don't store hashes, so the keys
+    /// we look at on the way are hashed again.
+    unsafe fn remove_at(&mut self, group: *mut Group<K, V>, pos: usize) -> V {
+        let mask = self.table.group_mask;
+        let mut hole_idx = (group as usize - self.table.groups.as_ptr() as usize) / Group::<K, V>::stride();
+        let was_full = (*group).is_full();
+        let (_, value) = (*group).take(pos);
+        self.table.size -= 1;
+
+        if was_full {
+            'holes: loop {
+                let hole = self.table.group(hole_idx);
+                let mut idx = (hole_idx + 1) & mask;
+                loop {
+                    let group = &mut *self.table.group(idx);
+                    let full = group.is_full();
+                    for i in 0..ENTRIES_PER_GROUP {
+                        if group.entry(i).0 == Group::<K, V>::sentinel() {
+                            continue;
+                        }
+                        let home = make_hash(&self.hash_builder, group.key(i)) as usize & mask;
+                        // The entry can move into the hole if the hole lies on its
+                        // probe sequence, between its home group and its current group.
+                        if (hole_idx.wrapping_sub(home) & mask) < (idx.wrapping_sub(home) & mask) {
+                            let (k, v) = group.take(i);
+                            let hole = &mut *hole;
+                            let free = hole.search_for_empty().unwrap();
+                            hole.set(free, k, v);
+                            if !full {
+                                break 'holes;
+                            }
+                            hole_idx = idx;
+                            continue 'holes;
+                        }
+                    }
+                    if !full {
+                        break 'holes;
+                    }
+                    idx = (idx + 1) & mask;
+                }
+            }
+        }
+        ptr::read(&value as *const u64 as *const V)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        if Self::is_sentinel(k) {
+            return self.sentinel.as_ref().map(|&(_, ref v)| v);
+        }
+        let spot = self.search(k);
         if spot.empty {
             None
         } else {
             unsafe {
-                Some(&*((*spot.group).values.get_unchecked(spot.pos) as *const _ as *const K))
+                Some(&*(*spot.group).value(spot.pos))
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        if Self::is_sentinel(k) {
+            return self.sentinel.as_mut().map(|&mut (_, ref mut v)| v);
+        }
+        let spot = self.search(k);
+        if spot.empty {
+            None
+        } else {
+            unsafe {
+                Some(&mut *(*spot.group).value(spot.pos))
+            }
+        }
+    }
+
+    /// Looks up `key` for inserting or updating its value in place. If the key isn't
+    /// there and the table is full, the table grows right away and is searched again,
+    /// otherwise the lookup is the only time the table is probed.
+    pub fn entry(&mut self, key: K) -> Entry<K, V, S, A> {
+        let (spot, empty) = if Self::is_sentinel(&key) {
+            (None, self.sentinel.is_none())
+        } else {
+            let mut spot = self.search(&key);
+            if spot.empty && self.table.size + 1 > self.table.capacity {
+                error::unwrap(self.try_expand());
+                spot = self.search(&key);
+            }
+            let empty = spot.empty;
+            (Some(spot), empty)
+        };
+        if empty {
+            Entry::Vacant(VacantEntry {
+                map: self,
+                spot,
+                key,
+            })
+        } else {
+            Entry::Occupied(OccupiedEntry {
+                map: self,
+                spot,
+                key,
+            })
+        }
+    }
+}
+
+pub enum Entry<'a, K: Hash + Sentinel + 'a, V: 'a, S: BuildHasher + 'a, A: Alloc + Clone + 'a = Global> {
+    Occupied(OccupiedEntry<'a, K, V, S, A>),
+    Vacant(VacantEntry<'a, K, V, S, A>),
+}
+
+impl<'a, K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> Entry<'a, K, V, S, A> {
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Calls `f` with the value if the key is in the map.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
             }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<'a, K: Hash + Sentinel, V: Default, S: BuildHasher, A: Alloc + Clone> Entry<'a, K, V, S, A> {
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+/// `spot` is `None` for the sentinel key, whose entry is kept outside the table.
+pub struct OccupiedEntry<'a, K: Hash + Sentinel + 'a, V: 'a, S: BuildHasher + 'a, A: Alloc + Clone + 'a = Global> {
+    map: &'a mut Map<K, V, S, A>,
+    spot: Option<RawEntry<K, V>>,
+    key: K,
+}
+
+impl<'a, K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> OccupiedEntry<'a, K, V, S, A> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> &V {
+        match self.spot {
+            Some(ref spot) => unsafe { &*(*spot.group).value(spot.pos) },
+            None => &self.map.sentinel.as_ref().unwrap().1,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        match self.spot {
+            Some(ref spot) => unsafe { &mut *(*spot.group).value(spot.pos) },
+            None => &mut self.map.sentinel.as_mut().unwrap().1,
+        }
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        match self.spot {
+            Some(spot) => unsafe { &mut *(*spot.group).value(spot.pos) },
+            None => &mut self.map.sentinel.as_mut().unwrap().1,
+        }
+    }
+
+    /// Replaces the value, returning the old one.
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        match self.spot {
+            Some(spot) => {
+                let value = unsafe { self.map.remove_at(spot.group, spot.pos) };
+                self.map.validate_change();
+                value
+            }
+            None => self.map.sentinel.take().unwrap().1,
+        }
+    }
+}
+
+/// `spot` is `None` for the sentinel key, whose entry is kept outside the table.
+pub struct VacantEntry<'a, K: Hash + Sentinel + 'a, V: 'a, S: BuildHasher + 'a, A: Alloc + Clone + 'a = Global> {
+    map: &'a mut Map<K, V, S, A>,
+    spot: Option<RawEntry<K, V>>,
+    key: K,
+}
+
+impl<'a, K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> VacantEntry<'a, K, V, S, A> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts `value` into the slot found by the lookup. `Map::entry` made room
+    /// for it, so the table doesn't grow here.
+    pub fn insert(self, value: V) -> &'a mut V {
+        let spot = match self.spot {
+            Some(spot) => spot,
+            None => {
+                self.map.sentinel = Some((self.key, value));
+                return &mut self.map.sentinel.as_mut().unwrap().1;
+            }
+        };
+        unsafe {
+            self.map.table.size += 1;
+            (*spot.group).set(spot.pos, bits(&self.key), bits(&value));
+            mem::forget(value);
+            self.map.validate_change();
+            &mut *(*spot.group).value(spot.pos)
+        }
+    }
+}
+
+/// The iterators yield the entry for the sentinel key first, then the ones in the table.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    sentinel: Option<&'a (K, V)>,
+    raw: RawIter<K, V>,
+    marker: PhantomData<(&'a K, &'a V)>,
+}
+
+impl<'a, K: Sentinel, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if let Some(&(ref k, ref v)) = self.sentinel.take() {
+            return Some((k, v));
+        }
+        unsafe {
+            self.raw.next().map(|(group, pos)| ((*group).key(pos), &*(*group).value(pos)))
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.raw.remaining + self.sentinel.is_some() as usize;
+        (len, Some(len))
+    }
+}
+
+impl<'a, K: Sentinel, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    sentinel: Option<&'a mut (K, V)>,
+    raw: RawIter<K, V>,
+    marker: PhantomData<(&'a K, &'a mut V)>,
+}
+
+impl<'a, K: Sentinel, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        if let Some(&mut (ref k, ref mut v)) = self.sentinel.take() {
+            return Some((k, v));
+        }
+        unsafe {
+            self.raw.next().map(|(group, pos)| ((*group).key(pos), &mut *(*group).value(pos)))
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.raw.remaining + self.sentinel.is_some() as usize;
+        (len, Some(len))
+    }
+}
+
+impl<'a, K: Sentinel, V> ExactSizeIterator for IterMut<'a, K, V> {}
+
+pub struct Keys<'a, K: 'a, V: 'a> {
+    iter: Iter<'a, K, V>,
+}
+
+impl<'a, K: Sentinel, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K: Sentinel, V> ExactSizeIterator for Keys<'a, K, V> {}
+
+pub struct Values<'a, K: 'a, V: 'a> {
+    iter: Iter<'a, K, V>,
+}
+
+impl<'a, K: Sentinel, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next().map(|(_, v)| v)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K: Sentinel, V> ExactSizeIterator for Values<'a, K, V> {}
+
+/// Groups are emptied one at a time, walking backwards from a group which isn't full.
+/// Probe sequences never run through that group, so none of them runs through the
+/// groups emptied so far and the entries left in the table can still be found. If
+/// the iterator is leaked the map keeps those, only the entries taken out of the
+/// current group are lost.
+pub struct Drain<'a, K: Sentinel + 'a, V: 'a, A: Alloc + 'a = Global> {
+    sentinel: &'a mut Option<(K, V)>,
+    table: &'a mut Table<K, V, A>,
+    group_idx: usize,
+    /// The bits of the entries taken out of the current group, and how many are left.
+    entries: [(u64, u64); ENTRIES_PER_GROUP],
+    taken: usize,
+}
+
+impl<'a, K: Sentinel, V, A: Alloc> Iterator for Drain<'a, K, V, A> {
+    type Item = (K, V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        if let Some(entry) = self.sentinel.take() {
+            return Some(entry);
+        }
+        while self.taken == 0 {
+            if self.table.size == 0 {
+                return None;
+            }
+            let group = unsafe { &mut *self.table.group(self.group_idx) };
+            for pos in 0..ENTRIES_PER_GROUP {
+                if group.entry(pos).0 != Group::<K, V>::sentinel() {
+                    self.entries[self.taken] = group.take(pos);
+                    self.taken += 1;
+                }
+            }
+            self.table.size -= self.taken;
+            self.group_idx = self.group_idx.wrapping_sub(1) & self.table.group_mask;
+        }
+        self.taken -= 1;
+        let (k, v) = self.entries[self.taken];
+        unsafe {
+            Some((from_bits(k), from_bits(v)))
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.table.size + self.taken + self.sentinel.is_some() as usize;
+        (len, Some(len))
+    }
+}
+
+impl<'a, K: Sentinel, V, A: Alloc> ExactSizeIterator for Drain<'a, K, V, A> {}
+
+impl<'a, K: Sentinel, V, A: Alloc> Drop for Drain<'a, K, V, A> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+        if cfg!(feature = "validate") {
+            // The table is empty now, so no keys have to be hashed
+            if let Err(err) = self.table.validate(|_, _| 0) {
+                panic!("invalid map: {}", err);
+            }
+        }
+    }
+}
+
+pub struct IntoIter<K: Sentinel, V, A: Alloc = Global> {
+    sentinel: Option<(K, V)>,
+    raw: RawIter<K, V>,
+    table: Table<K, V, A>,
+}
+
+impl<K: Sentinel, V, A: Alloc> Iterator for IntoIter<K, V, A> {
+    type Item = (K, V);
+
+    #[inline]
+    fn next(&mut self) -> Option<(K, V)> {
+        if let Some(entry) = self.sentinel.take() {
+            return Some(entry);
+        }
+        unsafe {
+            self.raw.take().map(|entry| {
+                self.table.size -= 1;
+                entry
+            })
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.raw.remaining + self.sentinel.is_some() as usize;
+        (len, Some(len))
+    }
+}
+
+impl<K: Sentinel, V, A: Alloc> ExactSizeIterator for IntoIter<K, V, A> {}
+
+impl<K: Sentinel, V, A: Alloc> Drop for IntoIter<K, V, A> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+pub struct Groups<'a, K: 'a, V: 'a> {
+    group: *const Group<K, V>,
+    remaining: usize,
+    marker: PhantomData<&'a Group<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Groups<'a, K, V> {
+    type Item = &'a Group<K, V>;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a Group<K, V>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        unsafe {
+            let group = &*self.group;
+            self.group = self.group.offset(1);
+            Some(group)
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Groups<'a, K, V> {}
+
+impl<'a, K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> IntoIterator for &'a Map<K, V, S, A> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> IntoIterator for &'a mut Map<K, V, S, A> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+impl<K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> IntoIterator for Map<K, V, S, A> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, A>;
+
+    fn into_iter(self) -> IntoIter<K, V, A> {
+        // `Map` drops the values when it is dropped, so we move the table out
+        // without running that and leave the values to the iterator
+        let mut map = mem::ManuallyDrop::new(self);
+        unsafe {
+            ptr::drop_in_place(&mut map.hash_builder);
+            let table = ptr::read(&map.table);
+            IntoIter {
+                sentinel: ptr::read(&map.sentinel),
+                raw: table.raw_iter(),
+                table,
+            }
+        }
+    }
+}
+
+impl<K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> Drop for Map<K, V, S, A> {
+    fn drop(&mut self) {
+        if mem::needs_drop::<V>() {
+            self.table.iter(|group, pos| unsafe {
+                ptr::drop_in_place(group.value(pos));
+            });
+        }
+    }
+}
+
+impl<K: Hash + Sentinel, V: Clone, S: BuildHasher + Clone, A: Alloc + Clone> Clone for Map<K, V, S, A> {
+    /// The keys are `Copy`, so only the values are cloned one by one.
+    fn clone(&self) -> Self {
+        Map {
+            hash_builder: self.hash_builder.clone(),
+            table: self.table.clone_with(|from, to, pos| unsafe {
+                ptr::write(to.value(pos), (*from.value(pos)).clone());
+            }),
+            sentinel: self.sentinel.clone(),
+            policy: self.policy,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Hash + Sentinel + Debug, V: Debug, S: BuildHasher, A: Alloc + Clone> Debug for Map<K, V, S, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Hash + Sentinel, V: PartialEq, S: BuildHasher, A: Alloc + Clone> PartialEq for Map<K, V, S, A> {
+    fn eq(&self, other: &Map<K, V, S, A>) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+        self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Hash + Sentinel, V: Eq, S: BuildHasher, A: Alloc + Clone> Eq for Map<K, V, S, A> {}
+
+impl<K: Hash + Sentinel, V, S: Default + BuildHasher, A: Alloc + Clone + Default> Default for Map<K, V, S, A> {
+    fn default() -> Self {
+        Map::new()
+    }
+}
+
+impl<K: Hash + Sentinel, V, S: BuildHasher, A: Alloc + Clone> Extend<(K, V)> for Map<K, V, S, A> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<K: Hash + Sentinel, V, S: Default + BuildHasher, A: Alloc + Clone + Default> FromIterator<(K, V)> for Map<K, V, S, A> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Map::new();
+        map.extend(iter);
+        map
+    }
+}
+
+/// Keeps the hasher of the `HashMap`.
+impl<K: Hash + Sentinel, V, S: BuildHasher + Clone, A: Alloc + Clone + Default> From<HashMap<K, V, S>> for Map<K, V, S, A> {
+    fn from(map: HashMap<K, V, S>) -> Self {
+        let mut result = Map::with_hasher(map.hasher().clone());
+        result.extend(map);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fx;
+    use std::hash::BuildHasherDefault;
+    use std::rc::Rc;
+
+    // With the identity hash, tests can pick the home group of a key
+    type PlainMap = Map<u64, u64, BuildHasherDefault<fx::PlainHasher>>;
+
+    #[test]
+    fn traits() {
+        let std: HashMap<u64, u64, _> = (1..20u64).map(|i| (i, i * 10)).collect();
+        let mut map = PlainMap::with_capacity(64);
+        map.extend(std.clone());
+        assert_eq!(map, PlainMap::from(std.clone()));
+        assert_eq!(map.clone(), map);
+        assert_eq!(map.len(), 19);
+
+        let mut other = map.clone();
+        other.insert(5, 0);
+        assert!(map != other);
+
+        let one: PlainMap = vec![(7, 70)].into_iter().collect();
+        assert_eq!(format!("{:?}", one), "{7: 70}");
+        assert_eq!(PlainMap::default().len(), 0);
+    }
+
+    #[test]
+    fn stats() {
+        let mut map = PlainMap::with_capacity(64);
+        map.extend((1..20u64).map(|i| (i, i)));
+        let stats = map.stats();
+        assert_eq!(stats.len, 19);
+        assert_eq!(stats.probe_lengths.iter().sum::<usize>(), 19);
+        assert_eq!(stats.group_sizes.iter().sum::<usize>(), stats.groups);
+        assert_eq!(stats.memory, stats.groups * 64);
+        assert_eq!(stats.tag_collisions, 0);
+    }
+
+    #[test]
+    fn policy() {
+        let mut map = PlainMap::with_capacity(64);
+        let slots = (map.table.group_mask + 1) * ENTRIES_PER_GROUP;
+        assert_eq!(map.table.capacity, ((slots - 1) * 10 + 9) / 13);
+        map.set_policy(Policy::new(1, 2, 2));
+        assert_eq!(map.table.capacity, (slots - 1) / 2);
+        assert_eq!(map.clone().policy, Policy::new(1, 2, 2));
+
+        // The requested capacity is what the policy allows, not the number of slots
+        let mut map = PlainMap::with_capacity(64);
+        let slots = (map.table.group_mask + 1) * ENTRIES_PER_GROUP;
+        map.extend((0..64u64).map(|i| (i, i)));
+        assert_eq!((map.table.group_mask + 1) * ENTRIES_PER_GROUP, slots);
+        map.reserve(64);
+        assert!(map.table.capacity >= 128);
+    }
+
+    #[test]
+    fn fallible_alloc() {
+        assert_eq!(PlainMap::try_with_capacity(usize::max_value()).err(),
+                   Some(CollectionAllocErr::CapacityOverflow));
+        let mut map = PlainMap::with_capacity(64);
+        assert_eq!(map.try_insert(1, 10), Ok(None));
+        assert_eq!(map.try_reserve(usize::max_value()), Err(CollectionAllocErr::CapacityOverflow));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn validate() {
+        let mut map = PlainMap::with_capacity(64);
+        map.extend((1..20u64).map(|i| (i, i)));
+        assert_eq!(map.validate(), Ok(()));
+        map.table.size += 1;
+        assert_eq!(map.validate(), Err("the size is 20, but 19 of the 128 slots don't hold \
+                                        the sentinel".to_string()));
+        // An entry for key 64 belongs in group 0, which is empty
+        unsafe {
+            (*map.table.group(3)).set(3, 64, 0);
+        }
+        assert_eq!(map.validate(), Err("group 3: the entry at 3 has home group 0, \
+                                        but group 0 before it isn't full".to_string()));
+
+        let mut map = PlainMap::with_capacity(64);
+        map.sentinel = Some((5, 0));
+        assert_eq!(map.validate(), Err("the entry kept outside the table has the key 0x5, \
+                                        which isn't the sentinel".to_string()));
+    }
+
+    #[test]
+    fn values() {
+        let mut map = PlainMap::new();
+        assert_eq!(map.insert(1, 10), None);
+        assert_eq!(map.insert(1, 11), Some(10));
+        *map.get_mut(&1).unwrap() += 1;
+        assert_eq!(map.get(&1), Some(&12));
+        assert_eq!(map.get_mut(&2), None);
+        // Growing rehashes the keys
+        map.extend((2..1000).map(|i| (i << 20, i)));
+        assert_eq!(map.len(), 999);
+        assert!((2..1000).all(|i| map.get(&(i << 20)) == Some(&i)));
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    fn check(map: &PlainMap, model: &HashMap<u64, u64>) -> bool {
+        map.validate().is_ok() &&
+            map.len() == model.len() &&
+            model.iter().all(|(k, v)| map.get(k) == Some(v))
+    }
+
+    #[test]
+    fn remove_wrapped_chain() {
+        let mut map = PlainMap::with_capacity(64);
+        let mut model = HashMap::new();
+        // Fill the table up to just before it expands with keys whose home is the
+        // last group, so probing wraps around to group 0
+        let last = map.table.group_mask as u64;
+        while map.len() + 1 <= map.table.capacity {
+            let k = last + ((map.len() as u64 + 1) << 20);
+            map.insert(k, k);
+            model.insert(k, k);
+        }
+        assert_eq!(map.remove(&(last + (1000 << 20))), None);
+        // Remove from the middle of the run first, then the rest
+        let mut keys: Vec<u64> = model.keys().cloned().collect();
+        keys.sort_by_key(|&k| (k >> 20) % 3);
+        for k in keys {
+            assert_eq!(map.remove(&k), model.remove(&k));
+            assert_eq!(map.remove(&k), None);
+            assert!(check(&map, &model));
+        }
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn remove_overflowed_chain() {
+        let mut map = PlainMap::new();
+        let mut model = HashMap::new();
+        // Keys with home groups 0 and 1 share one run of full groups
+        for i in 1..40u64 {
+            let k = (i & 1) | (i << 20);
+            map.insert(k, i);
+            model.insert(k, i);
+        }
+        for i in (1..40u64).filter(|i| i % 3 == 0) {
+            let k = (i & 1) | (i << 20);
+            assert_eq!(map.remove(&k), Some(i));
+            model.remove(&k);
+            assert!(check(&map, &model));
+        }
+    }
+
+    #[test]
+    fn entry() {
+        let mut map = Map::<u64, u64, BuildHasherDefault<fx::FxHasher>>::new();
+        for i in 0..1000u64 {
+            *map.entry(i % 10).or_insert(0) += 1;
+        }
+        assert_eq!(map.len(), 10);
+        assert!((0..10).all(|i| map.get(&i) == Some(&100)));
+
+        assert_eq!(*map.entry(20).or_insert_with(|| 7), 7);
+        assert_eq!(*map.entry(20).or_insert_with(|| unreachable!()), 7);
+        assert_eq!(*map.entry(21).or_default(), 0);
+        assert_eq!(*map.entry(21).and_modify(|v| *v += 5).or_default(), 5);
+        map.entry(22).and_modify(|_| unreachable!());
+        assert_eq!(map.get(&22), None);
+        assert_eq!(*map.entry(23).key(), 23);
+
+        match map.entry(20) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.insert(8), 7);
+                assert_eq!(*entry.get(), 8);
+                assert_eq!(entry.remove(), 8);
+            }
+            Entry::Vacant(_) => panic!(),
+        }
+        match map.entry(20) {
+            Entry::Vacant(entry) => assert_eq!(entry.into_key(), 20),
+            Entry::Occupied(_) => panic!(),
+        }
+        assert_eq!(map.len(), 11);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn entry_probes_once() {
+        let mut map = PlainMap::with_capacity(64);
+        let probes = || table::PROBES.with(|probes| probes.get());
+        let before = probes();
+        *map.entry(1).or_insert(0) += 1;
+        *map.entry(1).or_insert(0) += 1;
+        map.entry(2).and_modify(|v| *v += 1).or_default();
+        assert_eq!(probes() - before, 3);
+        assert_eq!(map.get(&1), Some(&2));
+
+        // Keys which are there don't make room for another, even in a full table
+        let room = map.table.capacity - map.len();
+        map.extend((3..).take(room).map(|i| (i, i)));
+        let group_mask = map.table.group_mask;
+        let before = probes();
+        *map.entry(1).or_insert(0) += 1;
+        assert_eq!(probes() - before, 1);
+        assert_eq!(map.table.group_mask, group_mask);
+        // A new key grows the table, which probes once for each entry it moves, and
+        // is looked up again
+        let len = map.len();
+        map.entry(1000).or_insert(0);
+        assert_eq!(probes() - before, 1 + 1 + len + 1);
+        assert!(map.table.group_mask > group_mask);
+        assert_eq!(map.get(&1000), Some(&0));
+    }
+
+    #[test]
+    fn sentinel_key() {
+        let max = u64::sentinel();
+        let mut map = PlainMap::new();
+        assert_eq!(map.get(&max), None);
+        assert_eq!(map.insert(max, 1), None);
+        assert_eq!(map.insert(max, 2), Some(1));
+        map.insert(3, 30);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.table.size, 1);
+        assert!(map.contains_key(&max));
+        assert_eq!(map.get(&max), Some(&2));
+        *map.get_mut(&max).unwrap() += 1;
+        assert_eq!(map.iter().len(), 2);
+        assert_eq!(map.iter().collect::<Vec<_>>(), vec![(&max, &3), (&3, &30)]);
+        for (_, v) in &mut map {
+            *v += 1;
+        }
+        assert_eq!(format!("{:?}", map), format!("{{{}: 4, 3: 31}}", max));
+        assert_eq!(map.clone(), map);
+        assert!(map != [(3, 31)].iter().cloned().collect());
+        assert_eq!(map.remove(&max), Some(4));
+        assert_eq!(map.remove(&max), None);
+        assert_eq!(map.len(), 1);
+
+        assert_eq!(*map.entry(max).or_insert(5), 5);
+        map.entry(max).and_modify(|v| *v += 1);
+        match map.entry(max) {
+            Entry::Occupied(e) => assert_eq!(e.remove(), 6),
+            Entry::Vacant(_) => panic!(),
+        }
+        assert!(!map.contains_key(&max));
+
+        map.insert(max, 7);
+        assert_eq!(map.drain().collect::<Vec<_>>(), vec![(max, 7), (3, 31)]);
+        assert_eq!(map.len(), 0);
+        map.insert(max, 8);
+        assert_eq!(map.into_iter().collect::<Vec<_>>(), vec![(max, 8)]);
+    }
+
+    #[test]
+    fn drops_values() {
+        let value = Rc::new(());
+        {
+            let mut map = Map::<u64, Rc<()>, BuildHasherDefault<fx::FxHasher>>::new();
+            for i in 0..100 {
+                map.insert(i, value.clone());
+            }
+            assert_eq!(Rc::strong_count(&value), 101);
+            drop(map.insert(7, value.clone()));
+            assert_eq!(Rc::strong_count(&value), 101);
+            drop(map.remove(&8));
+            assert_eq!(Rc::strong_count(&value), 100);
+            map.insert(u64::sentinel(), value.clone());
+            assert_eq!(Rc::strong_count(&value), 101);
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn iter() {
+        let mut map = PlainMap::new();
+        let mut model = fx::FxHashMap::default();
+        for i in 0..200 {
+            map.insert(i * 3, i);
+            model.insert(i * 3, i);
+        }
+        map.remove(&30);
+        model.remove(&30);
+        assert_eq!(map.iter().len(), 199);
+        assert_eq!(map.iter().map(|(&k, &v)| (k, v)).collect::<fx::FxHashMap<_, _>>(), model);
+        assert_eq!(map.keys().map(|&k| k).sum::<u64>(), model.keys().sum::<u64>());
+        assert_eq!(map.values().map(|&v| v).sum::<u64>(), model.values().sum::<u64>());
+
+        for (_, v) in &mut map {
+            *v += 1;
+        }
+        for (k, v) in map.iter_mut() {
+            assert_eq!(*v, model[k] + 1);
+            *v = *k;
+        }
+        assert!((&map).into_iter().all(|(k, v)| k == v));
+        assert_eq!(PlainMap::new().iter().next(), None);
+    }
+
+    #[test]
+    fn groups() {
+        let mut map = PlainMap::with_capacity(64);
+        for i in 0..50 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.groups().len(), map.table.group_mask + 1);
+        let sentinel = Group::<u64, u64>::sentinel();
+        let mut entries = 0;
+        for group in map.groups() {
+            for (&k, &v) in group.keys().iter().zip(group.values().iter()) {
+                if k != sentinel {
+                    assert_eq!(v, k * 2);
+                    entries += 1;
+                }
+            }
+        }
+        assert_eq!(entries, 50);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn sse41_matches(group: &Group<u64, u64>, key: u64) -> u32 {
+        Sse41::matches(group, key)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn avx2_matches(group: &Group<u64, u64>, key: u64) -> u32 {
+        Avx2::matches(group, key)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn simd_matches_scalar() {
+        let mut map = PlainMap::with_capacity(64);
+        // Keys which differ only in one half, so both halves have to be compared
+        for i in 1..40u64 {
+            map.insert(i, i);
+            map.insert(i << 32, i);
+        }
+        map.remove(&7);
+        let mut keys = vec![u64::sentinel(), 0, 7, 1 << 32 | 1, !0 - 1];
+        keys.extend(map.keys().cloned());
+        for group in map.groups() {
+            for &k in &keys {
+                let expected = unsafe { Scalar::matches(group, k) };
+                if simd::supported(simd::Level::Sse41) {
+                    assert_eq!(unsafe { sse41_matches(group, k) }, expected);
+                }
+                if simd::supported(simd::Level::Avx2) {
+                    assert_eq!(unsafe { avx2_matches(group, k) }, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drain() {
+        let value = Rc::new(());
+        let mut map = Map::<u64, Rc<()>, BuildHasherDefault<fx::FxHasher>>::new();
+        for i in 0..100 {
+            map.insert(i, value.clone());
+        }
+        let mut drain = map.drain();
+        assert_eq!(drain.len(), 100);
+        let mut keys: Vec<_> = drain.by_ref().take(10).map(|(k, _)| k).collect();
+        assert_eq!(drain.len(), 90);
+        assert_eq!(Rc::strong_count(&value), 91);
+        drop(drain);
+        assert_eq!(Rc::strong_count(&value), 1);
+        assert_eq!(map.len(), 0);
+        assert!(map.validate().is_ok());
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 10);
+
+        map.insert(5, value.clone());
+        assert_eq!(map.get(&5).map(|v| Rc::ptr_eq(v, &value)), Some(true));
+        assert_eq!(map.drain().count(), 1);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn leaked_drain() {
+        // Keys with the home group 0 make a long probe sequence
+        let mut map: PlainMap = (0..60u64).map(|i| (i << 32, i)).collect();
+        map.extend((1..40u64).map(|i| (i, i)));
+        map.insert(u64::sentinel(), 0);
+        let mut drain = map.drain();
+        let drained: Vec<_> = drain.by_ref().take(30).collect();
+        let left = drain.len();
+        mem::forget(drain);
+        assert_eq!(map.validate(), Ok(()));
+        assert!(map.len() <= left && map.len() + ENTRIES_PER_GROUP > left);
+        assert!(map.iter().all(|(k, v)| map.get(k) == Some(v)));
+        assert!(drained.iter().all(|&(k, _)| map.get(&k).is_none()));
+        let len = map.len();
+        assert_eq!(map.drain().count(), len);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn clone_values() {
+        let value = Rc::new(());
+        let mut map = Map::<u64, Rc<()>, BuildHasherDefault<fx::FxHasher>>::new();
+        for i in 0..100 {
+            map.insert(i, value.clone());
+        }
+        map.insert(u64::sentinel(), value.clone());
+        let clone = map.clone();
+        assert_eq!(Rc::strong_count(&value), 203);
+        assert_eq!(clone.len(), 101);
+        assert_eq!(clone.validate(), Ok(()));
+        drop(map);
+        assert!(clone.get(&5).map_or(false, |v| Rc::ptr_eq(v, &value)));
+        drop(clone);
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn into_iter() {
+        let value = Rc::new(());
+        let mut map = Map::<u64, Rc<()>, BuildHasherDefault<fx::FxHasher>>::new();
+        for i in 0..100 {
+            map.insert(i, value.clone());
+        }
+        let mut iter = map.into_iter();
+        let mut sum = 0;
+        for (k, _) in iter.by_ref().take(30) {
+            sum += k;
+        }
+        assert_eq!(iter.len(), 70);
+        assert_eq!(Rc::strong_count(&value), 71);
+        sum += iter.map(|(k, _)| k).sum::<u64>();
+        assert_eq!(sum, 4950);
+        assert_eq!(Rc::strong_count(&value), 1);
+
+        let mut map = Map::<u64, Rc<()>, BuildHasherDefault<fx::FxHasher>>::new();
+        map.insert(1, value.clone());
+        map.insert(2, value.clone());
+        drop(map.into_iter());
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+
+    /// Runs a sequence of operations on a `Map` and an `FxHashMap` and compares them after each one.
+    /// `key` turns the keys from quickcheck into the keys used, given the group mask of the map.
+    fn ops_match_std<S, F>(ops: &[(u8, u64, u64)], mut key: F) -> bool
+        where S: BuildHasher + Default, F: FnMut(u64, usize) -> u64
+    {
+        let mut map = Map::<u64, u64, S>::new();
+        let mut model = fx::FxHashMap::default();
+        for &(op, k, v) in ops {
+            let k = key(k, map.table.group_mask);
+            let ok = match op % 6 {
+                0 | 1 => map.insert(k, v) == model.insert(k, v),
+                2 => map.get(&k) == model.get(&k) && map.contains_key(&k) == model.contains_key(&k),
+                3 => map.remove(&k) == model.remove(&k),
+                4 => match map.entry(k) {
+                    Entry::Occupied(e) => Some(e.remove()) == model.remove(&k),
+                    Entry::Vacant(e) => *e.insert(v) == v && model.insert(k, v).is_none(),
+                },
+                _ => {
+                    if let Some(v) = map.get_mut(&k) {
+                        *v += 1;
+                    }
+                    if let Some(v) = model.get_mut(&k) {
+                        *v += 1;
+                    }
+                    map.get(&k) == model.get(&k)
+                }
+            };
+            if !ok || map.validate().is_err() || map.len() != model.len() ||
+                model.iter().any(|(k, v)| map.get(k) != Some(v)) ||
+                map.iter().map(|(&k, &v)| (k, v)).collect::<fx::FxHashMap<_, _>>() != model {
+                return false;
+            }
+        }
+        map.into_iter().collect::<fx::FxHashMap<_, _>>() == model
+    }
+
+    quickcheck! {
+        fn ops_match_std_plain(ops: Vec<(u8, u64, u64)>) -> bool {
+            // Keys either share home group 0, have the last group as home so probing
+            // wraps around, or are spread out
+            ops_match_std::<BuildHasherDefault<fx::PlainHasher>, _>(&ops, |k, group_mask| {
+                match k % 3 {
+                    0 => (k & 0xff) << 20,
+                    1 => group_mask as u64 + ((k & 0xff) << 20),
+                    _ => k & 0xff,
+                }
+            })
+        }
+
+        fn ops_match_std_fx(ops: Vec<(u8, u64, u64)>) -> bool {
+            ops_match_std::<BuildHasherDefault<fx::FxHasher>, _>(&ops, |k, _| k % 512)
+        }
+
+        fn ops_match_std_fx_wide(ops: Vec<(u8, u64, u64)>) -> bool {
+            ops_match_std::<BuildHasherDefault<fx::FxHasher>, _>(&ops, |k, _| k)
+        }
+
+        fn ops_match_std_sentinel(ops: Vec<(u8, u64, u64)>) -> bool {
+            // Keys from the whole u64 range, with the sentinel and its neighbours often
+            ops_match_std::<BuildHasherDefault<fx::FxHasher>, _>(&ops, |k, _| {
+                match k % 4 {
+                    0 => u64::sentinel(),
+                    1 => u64::sentinel() - (k >> 2) % 8,
+                    _ => k.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+                }
+            })
         }
     }
 }
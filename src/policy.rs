@@ -0,0 +1,64 @@
+/// When tables grow and by how much.
+///
+/// A table grows once more than `max_load_num / max_load_den` of its slots are
+/// full, and one slot is always left empty so probes end. It then grows by a
+/// factor of `1 << growth_shift`, since the number of groups has to stay a power
+/// of two. Lower loads make probe sequences shorter at the cost of memory, and
+/// growing by more than a factor of 2 spends less time moving entries while a
+/// table is filled up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    max_load_num: usize,
+    max_load_den: usize,
+    growth_shift: u32,
+}
+
+impl Policy {
+    pub fn new(max_load_num: usize, max_load_den: usize, growth_shift: u32) -> Policy {
+        assert!(max_load_num > 0 && max_load_num <= max_load_den);
+        assert!(growth_shift > 0 && growth_shift < 8);
+        Policy {
+            max_load_num,
+            max_load_den,
+            growth_shift,
+        }
+    }
+
+    pub fn growth_shift(&self) -> u32 {
+        self.growth_shift
+    }
+
+    /// The number of entries we allow in a table with `slots` slots before it grows.
+    pub fn capacity(&self, slots: usize) -> usize {
+        let capacity1 = slots - 1;
+        // This is `(capacity1 * num + num - 1) / den`, split up so it can't overflow
+        let whole = capacity1 / self.max_load_den * self.max_load_num;
+        let rest = capacity1 % self.max_load_den * self.max_load_num + self.max_load_num - 1;
+        let capacity = whole + rest / self.max_load_den;
+        assert!(capacity < slots);
+        capacity
+    }
+}
+
+impl Default for Policy {
+    /// Grows when the table is 10/13 full, by doubling it.
+    fn default() -> Policy {
+        Policy::new(10, 13, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity() {
+        let policy = Policy::default();
+        assert_eq!(policy.capacity(64), (63 * 10 + 9) / 13);
+        assert_eq!(policy.capacity(1), 0);
+        let max = isize::max_value() as usize;
+        assert_eq!(policy.capacity(max) as u128, ((max as u128 - 1) * 10 + 9) / 13);
+        assert_eq!(Policy::new(7, 8, 1).capacity(max), max - max / 8 - 1);
+        assert_eq!(Policy::new(1, 1, 1).capacity(max), max - 1);
+    }
+}
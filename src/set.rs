@@ -3,13 +3,21 @@ use std::hash::Hash;
 use std::hash::Hasher;
 use std::hash::BuildHasher;
 use std::hash::BuildHasherDefault;
-use std::mem::{self, size_of};
-use std::ptr::{Unique, NonNull};
+use std::mem::{self, size_of, align_of};
+use std::cmp;
+use std::ptr::{self, Unique, NonNull};
 use std::alloc::{Global, Alloc};
 use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
 use std::borrow::Borrow;
-use std::fmt::Debug;
+use std::iter::FromIterator;
+use std::fmt::{self, Debug};
 use fx;
+use simd;
+use error::{self, CollectionAllocErr};
+use stats::{self, Stats};
+use policy::Policy;
+use table::{self, Group as TableGroup};
 
 /// A hash that is not zero, since we use a hash of zero to represent empty
 /// buckets.
@@ -38,6 +46,117 @@ impl SafeHash {
     }
 }
 
+/// The hashes stored in the groups, either `u32` or `u64`.
+///
+/// The low bits of a hash pick the home group of an entry, and the whole hash is
+/// compared when searching a group. With `u32` hashes those overlap, so entries
+/// sharing a home group also share the low bits of their hashes, and the bits left
+/// to tell them apart shrink as the table grows. `u64` hashes keep the index and the
+/// rest of the hash apart, which avoids most calls to `eq` on keys which don't match,
+/// and don't limit the table to 2^32 groups. They take up more room, so groups
+/// hold fewer entries.
+pub trait HashWord: Copy + Eq + Debug {
+    /// The number of bits of the hash kept in the stored word.
+    const BITS: u32;
+
+    /// Turns the output of a hasher into a hash we store in the table. This is never zero.
+    /// Doing this again on an already converted hash gives the same hash.
+    fn new(hash: u64) -> Self;
+
+    /// The hash of an empty slot.
+    fn zero() -> Self;
+
+    /// The hash as a `u64`. Its low bits pick the home group.
+    fn get(self) -> u64;
+
+    /// Compares the 16 bytes at `hashes` with `hash` and returns one bit per hash.
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn sse2_matches(hashes: *const Self, hash: Self) -> u32;
+
+    /// Compares the 32 bytes at `hashes` with `hash` and returns one bit per hash.
+    /// Only call this from functions with the `avx2` target feature enabled.
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn avx2_matches(hashes: *const Self, hash: Self) -> u32;
+}
+
+impl HashWord for u32 {
+    const BITS: u32 = 31;
+
+    #[inline(always)]
+    fn new(hash: u64) -> Self {
+        SafeHash::new(hash as u32).inspect()
+    }
+
+    #[inline(always)]
+    fn zero() -> Self {
+        0
+    }
+
+    #[inline(always)]
+    fn get(self) -> u64 {
+        self as u64
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    unsafe fn sse2_matches(hashes: *const Self, hash: Self) -> u32 {
+        use std::arch::x86_64::*;
+        let hashes = _mm_loadu_si128(hashes as *const __m128i);
+        let eq = _mm_cmpeq_epi32(hashes, _mm_set1_epi32(hash as i32));
+        _mm_movemask_ps(_mm_castsi128_ps(eq)) as u32
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    unsafe fn avx2_matches(hashes: *const Self, hash: Self) -> u32 {
+        use std::arch::x86_64::*;
+        let hashes = _mm256_loadu_si256(hashes as *const __m256i);
+        let eq = _mm256_cmpeq_epi32(hashes, _mm256_set1_epi32(hash as i32));
+        _mm256_movemask_ps(_mm256_castsi256_ps(eq)) as u32
+    }
+}
+
+impl HashWord for u64 {
+    const BITS: u32 = 63;
+
+    #[inline(always)]
+    fn new(hash: u64) -> Self {
+        // Like `SafeHash`, set the MSB so the hash isn't zero
+        (1 << 63) | hash
+    }
+
+    #[inline(always)]
+    fn zero() -> Self {
+        0
+    }
+
+    #[inline(always)]
+    fn get(self) -> u64 {
+        self
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    unsafe fn sse2_matches(hashes: *const Self, hash: Self) -> u32 {
+        use std::arch::x86_64::*;
+        // SSE2 has no 64-bit compare, so compare the halves and
+        // check that both halves of a hash are equal
+        let hashes = _mm_loadu_si128(hashes as *const __m128i);
+        let eq = _mm_cmpeq_epi32(hashes, _mm_set1_epi64x(hash as i64));
+        let eq = _mm_and_si128(eq, _mm_shuffle_epi32(eq, 0b10_11_00_01));
+        _mm_movemask_pd(_mm_castsi128_pd(eq)) as u32
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    unsafe fn avx2_matches(hashes: *const Self, hash: Self) -> u32 {
+        use std::arch::x86_64::*;
+        let hashes = _mm256_loadu_si256(hashes as *const __m256i);
+        let eq = _mm256_cmpeq_epi64(hashes, _mm256_set1_epi64x(hash as i64));
+        _mm256_movemask_pd(_mm256_castsi256_pd(eq)) as u32
+    }
+}
+
 /*
 const ENTRIES_PER_GROUP: usize = 5;
 
@@ -49,31 +168,70 @@ pub struct Group {
     values: [u64; ENTRIES_PER_GROUP],
 }
 */
-const ENTRIES_PER_GROUP: usize = 5;
-
-// Make Hashtable generic over the Group, so we can have one Group for 32-bit keys, 64-bit values etc.
-
-// Group has a u32 unused. Store metadata there?
-// Store a bool if the group is full, so we don't need to find that out
-#[repr(align(64), C)]
-pub struct Group {
-    hashes: [u32; ENTRIES_PER_GROUP],
-    //padding1: u32,
+/// The header of a group. The hashes and then the values follow it in memory,
+/// so the group looks like:
+///
+/// ```text
+/// size: u32,
+/// hashes: [H; Group::<K, H>::entries()],
+/// values: [K; Group::<K, H>::entries()],
+/// ```
+///
+/// The size takes up as much room as a hash, so the hashes stay aligned.
+/// The number of entries is picked based on the size of `K` so that a group fills
+/// a 64-byte cache line. Keys too large for that get a single entry per group
+/// and the group spans as few cache lines as possible.
+#[repr(C)]
+pub struct Group<K, H> {
     size: u32,
-    //padding3: u64,
-    values: [u64; ENTRIES_PER_GROUP],
+    marker: PhantomData<(K, H)>,
 }
 
-impl Group {
+impl<K, H: HashWord> Group<K, H> {
     #[inline(always)]
-    fn search_for_empty(&self) -> Option<usize> {
-        if self.size != ENTRIES_PER_GROUP as u32 {
-            Some(self.size as usize)
-        } else {
-            None
+    fn values_offset(entries: usize) -> usize {
+        let align = align_of::<K>();
+        (size_of::<H>() * (1 + entries) + align - 1) & !(align - 1)
+    }
+
+    #[inline(always)]
+    fn hashes(&self) -> *const H {
+        unsafe {
+            (self as *const Self as *const H).offset(1)
         }
-        //self.values[..].iter().position(|&v| v == 0)
     }
+
+    #[inline(always)]
+    fn hashes_mut(&mut self) -> *mut H {
+        unsafe {
+            (self as *mut Self as *mut H).offset(1)
+        }
+    }
+
+    #[inline(always)]
+    fn values(&self) -> *const K {
+        unsafe {
+            (self as *const Self as *const u8).offset(Self::values_offset(Self::entries()) as isize) as *const K
+        }
+    }
+
+    #[inline(always)]
+    fn values_mut(&mut self) -> *mut K {
+        unsafe {
+            (self as *mut Self as *mut u8).offset(Self::values_offset(Self::entries()) as isize) as *mut K
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn hash(&self, pos: usize) -> H {
+        *self.hashes().offset(pos as isize)
+    }
+
+    #[inline(always)]
+    unsafe fn value(&self, pos: usize) -> &K {
+        &*self.values().offset(pos as isize)
+    }
+
 /*
     #[inline(always)]
     #[target_feature(enable = "avx2")]
@@ -97,324 +255,1019 @@ impl Group {
     }
 */
 
+    /// Looks for an entry with `hash` for which `eq`, given its position, returns true,
+    /// comparing the hashes with `M`. Returns `(pos, false)` if there is one and
+    /// `(pos, true)` with an empty slot if there isn't, or `None` if the group is full.
     #[inline(always)]
-    fn search_with<K, F: FnMut(&K) -> bool>(&self, eq: &mut F, hash: u32) -> Option<(usize, bool)> {
-        // This unrolls
-        for i in 0..ENTRIES_PER_GROUP {
-            let h = unsafe { *self.hashes.get_unchecked(i) };
-            if h == hash && eq(unsafe { mem::transmute(self.values.get_unchecked(i)) }) {
+    fn search_matching<M: Matcher, F: FnMut(usize) -> bool>(&self, eq: &mut F, hash: H) -> Option<(usize, bool)> {
+        let mut mask = unsafe { M::matches(self, hash) };
+        while mask != 0 {
+            let i = mask.trailing_zeros() as usize;
+            if eq(i) {
                 return Some((i, false))
             }
+            mask &= mask - 1;
         }
         self.search_for_empty().map(|i| (i, true))
     }
-/*
-    //#[inline(never)]
-    //#[target_feature(enable = "sse4.1", enable = "avx2", enable = "bmi1")]
+
+
+    /// Removes the entry at `pos` and moves the last entry into its place,
+    /// so the occupied slots stay packed in `0..size`.
     #[inline(always)]
-    unsafe fn search_with<K, F: FnMut(&K) -> bool>(&self, eq_test: &mut F, hash: u32) -> Option<(usize, bool)> {
-        use std::arch::x86_64::*;
+    fn take(&mut self, pos: usize) -> (H, K) {
+        debug_assert!(pos < self.size as usize);
+        let last = self.size as usize - 1;
         unsafe {
-            //println!("checking for hash {} hashes {:?} values {:?}", hash, self.hashes, self.values);
-               /* 
-            for (i, &h) in self.hashes[..].iter().enumerate() {
-                println!("checking for hash {}: {} val: {}", i, h, self.values[i]);
-            }*/
-
-            let hashes = _mm_load_si128(&self.hashes as *const _ as *const _);
-            let hash_s = _mm_set1_epi32(hash as i32);
-            let eq = _mm_cmpeq_epi32(hash_s, hashes);
-            ///println!("eq {:?}", eq);
-            //let eq = _mm_packs_epi32(eq, eq);
-            //println!("eq1 {:?}", eq);
-            //let eq = _mm_cvtsi128_si64(eq);
-            //let eq = _mm_packs_epi16(eq, eq);
-            //println!("eq2 {:?}", eq);
-            //let mut mask = (_mm_movemask_epi8(eq) & 0xF) as u8;
-            let mut mask = _mm_movemask_epi8(eq) as u32;
-            //println!("mask {} {}", mask, _mm_movemask_epi8(eq));
-            let mut i = 0;
+            let hashes = self.hashes_mut();
+            let values = self.values_mut();
+            let h = *hashes.offset(pos as isize);
+            let v = ptr::read(values.offset(pos as isize));
+            *hashes.offset(pos as isize) = *hashes.offset(last as isize);
+            ptr::copy(values.offset(last as isize), values.offset(pos as isize), 1);
+            *hashes.offset(last as isize) = H::zero();
+            self.size -= 1;
+            (h, v)
+        }
+    }
+}
 
-            for i in 0..4 {
-                if (mask & (1 << (4 * i as u32)) != 0) && eq_test(unsafe { mem::transmute(self.values.get_unchecked(i)) }) {
-                    return Some((i, false))
-                }
-            }
+impl<K, H: HashWord> TableGroup for Group<K, H> {
+    type Entry = K;
+    type Tag = H;
+
+    #[inline(always)]
+    fn entries() -> usize {
+        let mut entries = (64 - size_of::<H>()) / (size_of::<H>() + size_of::<K>());
+        while entries > 1 && Self::values_offset(entries) + entries * size_of::<K>() > 64 {
+            entries -= 1;
+        }
+        cmp::max(entries, 1)
+    }
+
+    /// Groups are only aligned to cache lines, so `K` can't need more than that.
+    #[inline(always)]
+    fn stride() -> usize {
+        assert!(align_of::<K>() <= 64);
+        let entries = Self::entries();
+        (Self::values_offset(entries) + entries * size_of::<K>() + 63) & !63
+    }
 
-            let h = unsafe { *self.hashes.get_unchecked(4) };
-            if h == hash && eq_test(unsafe { mem::transmute(self.values.get_unchecked(4)) }) {
-                return Some((4, false))
+    #[inline(always)]
+    fn clear(&mut self) {
+        for i in 0..Self::entries() {
+            unsafe {
+                *self.hashes_mut().offset(i as isize) = H::zero();
             }
-            /*
-            loop {
-                let skip = std::intrinsics::cttz(mask);
-                if skip == 32 {
-                    //println!("no hash matched");
-                    break;
-                }
-                i += (skip >> 2) as usize;
-                mask = mask >> (skip + 4);
-                //println!("testing idx {} rem {} skip {}", i, mask, skip);
-                if eq_test(unsafe { mem::transmute(self.values.get_unchecked(i)) }) {
-                    //println!("found hash at {}", i);
-                    return Some((i, false))
-                }
-                i += 1;
-            }*/
-            //println!("did not find hash");
+        }
+        self.size = 0;
+    }
 
+    #[inline(always)]
+    fn search_for_empty(&self) -> Option<usize> {
+        if self.size != Self::entries() as u32 {
+            Some(self.size as usize)
+        } else {
+            None
         }
-        self.search_for_empty().map(|i| (i, true))
+        //self.values[..].iter().position(|&v| v == 0)
     }
-*/
+
     #[inline(always)]
-    fn set(&mut self, pos: usize, hash: u32, value: u64) {
+    fn set(&mut self, pos: usize, hash: H, value: K) {
         unsafe {
-            *self.hashes.get_unchecked_mut(pos) = hash;
-            *self.values.get_unchecked_mut(pos) = value;
+            *self.hashes_mut().offset(pos as isize) = hash;
+            ptr::write(self.values_mut().offset(pos as isize), value);
         }
     }
 
     #[inline(always)]
-    fn iter<F: FnMut(u32, u64)>(&self, f: &mut F) {
-        for i in 0..ENTRIES_PER_GROUP {
-            unsafe {
-                let h = *self.hashes.get_unchecked(i);
-                if h != 0 {
-                    f(h, *self.values.get_unchecked(i))
-                }
+    fn iter<F: FnMut(usize)>(&self, mut f: F) {
+        for pos in 0..(self.size as usize) {
+            if unsafe { self.hash(pos) } != H::zero() {
+                f(pos);
             }
         }
     }
 }
 
-pub struct Table {
-    group_mask: usize,
-    size: usize,
-    capacity: usize,
-    groups: Unique<Group>,
+/// Finds the slots in a group whose hash is equal to `hash` and returns them as a bitmask.
+trait Matcher {
+    unsafe fn matches<K, H: HashWord>(group: &Group<K, H>, hash: H) -> u32;
 }
 
-#[derive(Debug)]
-pub struct RawEntry {
-    group: *mut Group,
-    pos: usize,
-    empty: bool
-}
-
-impl Table {
-    /// Does not initialize the buckets. The caller should ensure they,
-    /// at the very least, set every hash to EMPTY_BUCKET.
-    /// Returns an error if it cannot allocate or capacity overflows.
-    unsafe fn new_uninitialized(group_count: usize) -> Table {
-        assert!(size_of::<Group>() == 64);
-        let groups: NonNull<Group> = Global.alloc_array(group_count).unwrap();
-        let capacity2 = group_count * ENTRIES_PER_GROUP;
-        let capacity1 = capacity2 - 1;
-        //let capacity = (capacity1 * 10 + 10 - 1) / 11;
-        let capacity = (capacity1 * 10 + 10 - 1) / 13;
-        //println!("capacity1 {} capacity {}", capacity1, capacity);
-        assert!(capacity < capacity2);
+struct Scalar;
 
-        for i in 0..group_count {
-            let group = unsafe {
-                &mut (*groups.as_ptr().offset(i as isize))
-            };
-            group.hashes = [0; ENTRIES_PER_GROUP];
-            group.size = 0;
+impl Matcher for Scalar {
+    #[inline(always)]
+    unsafe fn matches<K, H: HashWord>(group: &Group<K, H>, hash: H) -> u32 {
+        let mut mask = 0;
+        // This unrolls
+        for i in 0..Group::<K, H>::entries() {
+            if group.hash(i) == hash {
+                mask |= 1 << i;
+            }
+        }
+        mask
+    }
+}
+
+/// Compares the hashes `bytes` at a time. The last load can read past the hashes
+/// into the values, which is fine as long as it stays inside the group. Those
+/// lanes are masked out afterwards.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn simd_fits<K, H: HashWord>(bytes: usize) -> bool {
+    let lanes = bytes / size_of::<H>();
+    let entries = Group::<K, H>::entries();
+    let loads = (entries + lanes - 1) / lanes;
+    size_of::<H>() * (1 + loads * lanes) <= Group::<K, H>::stride()
+}
+
+#[cfg(target_arch = "x86_64")]
+struct Sse2;
+
+#[cfg(target_arch = "x86_64")]
+impl Matcher for Sse2 {
+    #[inline(always)]
+    unsafe fn matches<K, H: HashWord>(group: &Group<K, H>, hash: H) -> u32 {
+        if !simd_fits::<K, H>(16) {
+            return Scalar::matches(group, hash);
+        }
+        let entries = Group::<K, H>::entries();
+        let lanes = 16 / size_of::<H>();
+        let mut mask = 0;
+        let mut i = 0;
+        while i < entries {
+            mask |= H::sse2_matches(group.hashes().offset(i as isize), hash) << i;
+            i += lanes;
+        }
+        mask & ((1 << entries) - 1)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+struct Avx2;
+
+#[cfg(target_arch = "x86_64")]
+impl Matcher for Avx2 {
+    /// Only call this from functions with the `avx2` target feature enabled.
+    #[inline(always)]
+    unsafe fn matches<K, H: HashWord>(group: &Group<K, H>, hash: H) -> u32 {
+        if !simd_fits::<K, H>(32) {
+            return Sse2::matches(group, hash);
         }
+        let entries = Group::<K, H>::entries();
+        let lanes = 32 / size_of::<H>();
+        let mut mask = 0;
+        let mut i = 0;
+        while i < entries {
+            mask |= H::avx2_matches(group.hashes().offset(i as isize), hash) << i;
+            i += lanes;
+        }
+        mask & ((1 << entries) - 1)
+    }
+}
+
+type Table<K, H, A> = table::Table<Group<K, H>, A>;
 
-        Table {
-            group_mask: group_count.wrapping_sub(1),
-            size: 0,
-            capacity,
-            groups: Unique::new_unchecked(groups.as_ptr()),
+type RawEntry<K, H> = table::RawEntry<Group<K, H>>;
+
+impl<K, H: HashWord, A: Alloc> Table<K, H, A> {
+    /// Finds the entry with `hash` for which `eq` returns true, comparing hashes
+    /// with the widest SIMD instructions available.
+    #[inline(always)]
+    fn lookup<F: FnMut(&K) -> bool>(&self, eq: F, hash: H) -> RawEntry<K, H> {
+        match simd::level() {
+            #[cfg(target_arch = "x86_64")]
+            simd::Level::Avx2 => unsafe { self.lookup_avx2(eq, hash) },
+            #[cfg(target_arch = "x86_64")]
+            simd::Level::Sse2 | simd::Level::Sse41 => self.lookup_with::<Sse2, F>(eq, hash),
+            _ => self.lookup_with::<Scalar, F>(eq, hash),
         }
     }
 
-    fn search_for_empty(&self, hash: u64) -> RawEntry {
-        //let group_idx = (hash >> 32) as usize;
-        let group_idx = hash as u32 as usize;
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn lookup_avx2<F: FnMut(&K) -> bool>(&self, eq: F, hash: H) -> RawEntry<K, H> {
+        self.lookup_with::<Avx2, F>(eq, hash)
+    }
+
+    #[inline(always)]
+    fn lookup_with<M: Matcher, F: FnMut(&K) -> bool>(&self, mut eq: F, hash: H) -> RawEntry<K, H> {
+        self.probe(hash.get(), |group| {
+            group.search_matching::<M, _>(&mut |pos| eq(unsafe { group.value(pos) }), hash)
+        })
+    }
+
+    /// Removes the entry at `group`/`pos` and returns its value.
+    ///
+    /// Lookups stop at the first group which isn't full, so freeing a slot in a full
+    /// group would hide entries which overflowed past it. We fix that up by moving
+    /// such an entry back into the hole, which opens a hole in its old group,
+    /// and repeat until we free a slot in a group which wasn't full.
+    unsafe fn remove(&mut self, group: *mut Group<K, H>, pos: usize) -> K {
         let mask = self.group_mask;
-        let mut group_idx = group_idx & mask;
+        let entries = Group::<K, H>::entries() as u32;
+        let mut group_idx = (group as usize - self.groups.as_ptr() as usize) / Group::<K, H>::stride();
+        let was_full = (*group).size == entries;
+        let (_, value) = (*group).take(pos);
+        self.size -= 1;
+        if !was_full {
+            return value;
+        }
 
-        loop {
-            //println!("checking group {}", group_idx);
-            let group_ptr = unsafe {
-                self.groups.as_ptr().offset(group_idx as isize)
-            };
-            let group = unsafe {
-                &(*group_ptr)
-            };
-            match unsafe { group.search_for_empty() } {
-                Some(pos) => return RawEntry {
-                    group: group_ptr,
-                    pos,
-                    empty: true,
-                },
-                None => (),
+        'holes: loop {
+            let hole = self.group(group_idx);
+            let mut idx = (group_idx + 1) & mask;
+            loop {
+                let group = &mut *self.group(idx);
+                for i in 0..(group.size as usize) {
+                    let h = group.hash(i);
+                    let home = h.get() as usize & mask;
+                    // The entry can move into the hole if the hole lies on its
+                    // probe sequence, between its home group and its current group.
+                    if (group_idx.wrapping_sub(home) & mask) < (idx.wrapping_sub(home) & mask) {
+                        let was_full = group.size == entries;
+                        let (h, v) = group.take(i);
+                        let hole = &mut *hole;
+                        let free = hole.size as usize;
+                        hole.set(free, h, v);
+                        hole.size += 1;
+                        if !was_full {
+                            break 'holes;
+                        }
+                        group_idx = idx;
+                        continue 'holes;
+                    }
+                }
+                if group.size != entries {
+                    break 'holes;
+                }
+                idx = (idx + 1) & mask;
             }
-            group_idx = (group_idx + 1) & mask;
         }
+        value
     }
 
-    fn search_with<K, F: FnMut(&K) -> bool>(&self, mut eq: F, hash: u64) -> RawEntry {
-        //let group_idx = (hash >> 32) as usize;
-        let group_idx = hash as u32 as usize;
-        let mask = self.group_mask;
-        let mut group_idx = group_idx & mask;
+    /// Moves the entries of group `group_idx` into `into`.
+    ///
+    /// Only the hashes of the group are cleared, its size is left alone. The
+    /// group looks full to probes, which then continue on to the entries that
+    /// overflowed past it and haven't been moved yet.
+    unsafe fn migrate_group(&mut self, group_idx: usize, into: &mut Table<K, H, A>) {
+        let group = &mut *self.group(group_idx);
+        for i in 0..(group.size as usize) {
+            let h = group.hash(i);
+            if h == H::zero() {
+                continue;
+            }
+            let spot = into.search_for_empty(h.get());
+            (*spot.group).size += 1;
+            (*spot.group).set(spot.pos, h, ptr::read(group.value(i)));
+            *group.hashes_mut().offset(i as isize) = H::zero();
+            into.size += 1;
+            self.size -= 1;
+        }
+    }
 
-        loop {
-            //println!("checking group {}", group_idx);
-            let group_ptr = unsafe {
-                self.groups.as_ptr().offset(group_idx as isize)
-            };
-            let group = unsafe {
-                &(*group_ptr)
-            };
-            let r = unsafe { group.search_with(&mut eq, hash as u32) } ;
-            //let r2 = unsafe { group.search_with2(&mut eq, hash as u32) } ;
-            //assert_eq!(r, r2);
-            //println!("search_with {}: {:?}", group_idx, r);
-            match r {
-                Some((pos, empty)) => return RawEntry {
-                    group: group_ptr,
-                    pos,
-                    empty,
-                },
-                None => (),
+    /// Removes an entry from a table we're migrating away from. Like `migrate_group`
+    /// this only clears the hash, so other probes aren't affected.
+    unsafe fn tombstone(&mut self, group: *mut Group<K, H>, pos: usize) -> K {
+        *(*group).hashes_mut().offset(pos as isize) = H::zero();
+        self.size -= 1;
+        ptr::read((*group).value(pos))
+    }
+
+    /// Grows the table by the factor given by `policy`, reallocating the group array
+    /// instead of building a second table.
+    ///
+    /// Since the group index is `hash & group_mask`, group `i` splits into `i`, `i + n`,
+    /// `i + 2n` and so on, and only the entries with one of the new mask bits set have to
+    /// move, see `split_group`. We split the groups in probe order, so an entry's home
+    /// group is always split before the group it overflowed into. Probe sequences end at
+    /// groups which aren't full, so starting right after one means the only run which
+    /// wraps around from the last group is handled last.
+    ///
+    /// The table is left as it was if this fails.
+    unsafe fn grow_in_place(&mut self, policy: &Policy) -> Result<(), CollectionAllocErr> {
+        let old_count = self.group_mask + 1;
+        let new_count = old_count.checked_mul(1 << policy.growth_shift())
+            .ok_or(CollectionAllocErr::CapacityOverflow)?;
+        let groups = self.alloc.realloc_array(
+            NonNull::new_unchecked(self.groups.as_ptr()),
+            Self::lines(old_count)?,
+            Self::lines(new_count)?
+        )?;
+        self.groups = Unique::new_unchecked(groups.as_ptr());
+        self.group_mask = new_count - 1;
+        self.capacity = Self::capacity(new_count, policy);
+
+        for i in old_count..new_count {
+            (*self.group(i)).clear();
+        }
+        // The policy always leaves a slot empty, so some group isn't full
+        let end = (0..old_count).find(|&i| {
+            (*self.group(i)).size != Group::<K, H>::entries() as u32
+        }).unwrap();
+        for i in 1..(old_count + 1) {
+            self.split_group((end + i) & (old_count - 1));
+        }
+        Ok(())
+    }
+
+    /// Splits a group of the table before it grew. Entries in their home group stay
+    /// and are packed at the start of it. The others are moved to the first empty slot
+    /// from their home group: the ones with a new mask bit set to a group in the new
+    /// part of the table, and the ones which overflowed past their home group back
+    /// towards it, since the groups in between may have lost entries. The entries are
+    /// read in order and the group is refilled from the start, so an entry put back
+    /// into this group only overwrites a slot we've already read.
+    unsafe fn split_group(&mut self, group_idx: usize) {
+        let group = self.group(group_idx);
+        let count = (*group).size as usize;
+        (*group).size = 0;
+        for i in 0..count {
+            let h = (*group).hash(i);
+            if h.get() as usize & self.group_mask == group_idx {
+                let pos = (*group).size as usize;
+                (*group).size += 1;
+                if pos != i {
+                    (*group).set(pos, h, ptr::read((*group).value(i)));
+                }
+                continue;
+            }
+            let value = ptr::read((*group).value(i));
+            let spot = self.search_for_empty(h.get());
+            (*spot.group).size += 1;
+            (*spot.group).set(spot.pos, h, value);
+        }
+        for i in ((*group).size as usize)..count {
+            *(*group).hashes_mut().offset(i as isize) = H::zero();
+        }
+    }
+
+    /// Adds the groups and entries of this table to `stats`.
+    fn add_stats(&self, stats: &mut Stats) {
+        let group_count = self.group_mask + 1;
+        let mut hashes = Vec::with_capacity(self.size);
+        let mut homes = vec![0usize; group_count];
+        for i in 0..group_count {
+            let group = unsafe { &*self.group(i) };
+            let mut size = 0;
+            for pos in 0..(group.size as usize) {
+                let hash = unsafe { group.hash(pos) };
+                // Skip entries already moved out of a table we're migrating away from
+                if hash == H::zero() {
+                    continue;
+                }
+                let home = hash.get() as usize & self.group_mask;
+                stats.add_probe(i.wrapping_sub(home) & self.group_mask);
+                homes[home] += 1;
+                hashes.push(hash.get());
+                size += 1;
             }
-            group_idx = (group_idx + 1) & mask;
+            stats.group_sizes[size] += 1;
         }
+        hashes.sort();
+        stats.tag_collisions += stats::equal_pairs(&hashes);
+        // Entries sharing a home group also share the bits of the hash which picked it
+        let free_bits = H::BITS.saturating_sub(group_count.trailing_zeros());
+        let pairs: usize = homes.iter().map(|&n| n * n.saturating_sub(1) / 2).sum();
+        stats.expected_tag_collisions += pairs as f64 / 2f64.powi(free_bits as i32);
+        stats.groups += group_count;
+        stats.memory += group_count * Group::<K, H>::stride();
     }
 
-    fn iter<F: FnMut(u32, u64)>(&self, mut f: F) {
+    /// Checks that keys are packed at the start of each group and then that lookups
+    /// find them, see `table::Table::validate`. Only a table we're migrating away from
+    /// may have zero hashes in `0..size` of a group, where keys were moved out.
+    fn validate_groups(&self, tombstones: bool) -> Result<(), String> {
+        let entries = Group::<K, H>::entries();
         for i in 0..(self.group_mask + 1) {
-            let group = unsafe {
-                &(*self.groups.as_ptr().offset(i as isize))
-            };
-            group.iter(&mut f);
+            let group = unsafe { &*self.group(i) };
+            let size = group.size as usize;
+            if size > entries {
+                return Err(format!("group {}: the size is {}, but a group has {} slots", i, size, entries));
+            }
+            let hashes = (0..size).filter(|&pos| unsafe { group.hash(pos) } != H::zero()).count();
+            if hashes != size && !tombstones {
+                return Err(format!("group {}: the size is {}, but {} hashes aren't zero", i, size, hashes));
+            }
+            if let Some(pos) = (size..entries).find(|&pos| unsafe { group.hash(pos) } != H::zero()) {
+                return Err(format!("group {}: the hash at {} isn't zero, but the size is {}", i, pos, size));
+            }
+        }
+        self.validate(|group, pos| unsafe { group.hash(pos) }.get())
+    }
+
+    /// Panics if `validate_groups` fails, with the `validate` feature.
+    #[inline(always)]
+    fn validate_change(&self, tombstones: bool) {
+        if cfg!(feature = "validate") {
+            if let Err(err) = self.validate_groups(tombstones) {
+                panic!("invalid set: {}", err);
+            }
+        }
+    }
+
+    fn raw_iter(&self) -> RawIter<K, H> {
+        RawIter {
+            group: self.group(0),
+            pos: 0,
+            remaining: self.size,
         }
     }
 }
 
-impl Drop for Table {
-    fn drop(&mut self) {
-        unsafe {
-            Global.dealloc_array(
-                NonNull::new_unchecked(self.groups.as_ptr()),
-                self.group_mask + 1
-            ).unwrap();
+/// Walks the groups of a table, yielding pointers to the occupied value slots.
+/// Entries are packed at the start of each group, so we only look at
+/// `0..size` of every group and stop once `remaining` entries have been seen.
+/// Slots with a zero hash are skipped, those only occur in a table we're migrating away from.
+struct RawIter<K, H> {
+    group: *const Group<K, H>,
+    pos: usize,
+    remaining: usize,
+}
+
+impl<K, H: HashWord> RawIter<K, H> {
+    fn empty() -> RawIter<K, H> {
+        RawIter {
+            group: ptr::null(),
+            pos: 0,
+            remaining: 0,
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn next(&mut self) -> Option<*const K> {
+        self.next_hashed().map(|(_, v)| v)
+    }
+
+    /// Like `next`, but also returns the hash stored for the entry.
+    #[inline(always)]
+    unsafe fn next_hashed(&mut self) -> Option<(H, *const K)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            let group = &*self.group;
+            if self.pos < group.size as usize {
+                let pos = self.pos;
+                self.pos += 1;
+                let hash = group.hash(pos);
+                if hash == H::zero() {
+                    continue;
+                }
+                self.remaining -= 1;
+                return Some((hash, group.values().offset(pos as isize)));
+            }
+            self.group = (self.group as *const u8)
+                .offset(Group::<K, H>::stride() as isize) as *const Group<K, H>;
+            self.pos = 0;
         }
     }
 }
 
-pub struct Set<K: Eq + Hash, S = RandomState> {
+/// The number of groups of the old table we move for each insertion during an
+/// incremental resize. The new table has at least twice the capacity of the old one,
+/// so this finishes the migration well before the new table has to grow.
+const MIGRATE_GROUPS: usize = 4;
+
+/// The table we're moving entries out of during an incremental resize.
+/// Groups before `next` have already been moved.
+struct Migration<K, H: HashWord, A: Alloc> {
+    table: Table<K, H, A>,
+    next: usize,
+}
+
+/// A hash set. `H` is the type of the hashes stored next to the keys, see `HashWord`.
+/// The tables are allocated with `A`.
+pub struct Set<K: Eq + Hash, S = RandomState, H: HashWord = u32, A: Alloc + Clone = Global> {
     hash_builder: S,
-    table: Table,
+    table: Table<K, H, A>,
+    old: Option<Migration<K, H, A>>,
+    incremental: bool,
+    policy: Policy,
     marker: PhantomData<K>,
 }
 
-impl<K: Eq + Hash, S: Default> Set<K, S> {
+impl<K: Eq + Hash, S: Default, H: HashWord, A: Alloc + Clone + Default> Set<K, S, H, A> {
     pub fn new() -> Self {
-        assert!(size_of::<K>() == 8);
-        Set {
-            hash_builder: S::default(),
-            table: unsafe { Table::new_uninitialized(2) },
-            marker: PhantomData,
-        }
+        Self::with_hasher(S::default())
     }
 
     pub fn with_capacity(s: usize) -> Self {
-        let groups = (s * ENTRIES_PER_GROUP + ENTRIES_PER_GROUP - 1) / ENTRIES_PER_GROUP;
-        let groups = groups.checked_next_power_of_two().unwrap();
-        assert!(size_of::<K>() == 8);
-        Set {
+        error::unwrap(Self::try_with_capacity(s))
+    }
+
+    /// Like `with_capacity`, but returns an error instead of panicking if the
+    /// table can't be allocated.
+    pub fn try_with_capacity(s: usize) -> Result<Self, CollectionAllocErr> {
+        let policy = Policy::default();
+        let groups = Table::<K, H, A>::group_count_for(s, &policy)?;
+        Ok(Set {
             hash_builder: S::default(),
-            table: unsafe { Table::new_uninitialized(groups) },
+            table: Table::new(groups, A::default(), &policy)?,
+            old: None,
+            incremental: false,
+            policy,
             marker: PhantomData,
-        }
+        })
     }
 }
 
-#[inline(never)]
-pub fn make_hash<T: ?Sized, S>(hash_state: &S, t: &T) -> u64
-    where T: Hash,
-          S: BuildHasher
-{
-    let mut state = hash_state.build_hasher();
-    t.hash(&mut state);
-    SafeHash::new(state.finish() as u32).inspect() as u64
+impl<K: Eq + Hash, S: Default, H: HashWord, A: Alloc + Clone> Set<K, S, H, A> {
+    pub fn new_in(alloc: A) -> Self {
+        Self::with_hasher_in(S::default(), alloc)
+    }
 }
 
-impl<K: Eq + Hash + Debug + Copy, S: BuildHasher> Set<K, S> {
-    #[inline(never)]
-    #[cold]
-    fn expand(&mut self) {
-        let mut new_table = unsafe {
-            Table::new_uninitialized((self.table.group_mask + 1) << 1)
-        };
-        // Expand the table in place and move only the entries whose mask change
-        // We need to move entries within a group in that case, might not be a win
-        new_table.size = self.table.size;
-        //println!("expanding to {}", (self.table.group_mask + 1) * ENTRIES_PER_GROUP);
-        self.table.iter(|h, v| {
-            let k = &v as *const _ as *const K;
-            //println!("moving {:?} with hash {}", unsafe { &*k }, h);
-            let spot = new_table.search_for_empty(h as u64);
-            unsafe {
-                (*spot.group).size += 1;
-                (*spot.group).set(spot.pos, h, v);
-            }
-            /*let spot = new_table.search_with::<K, _>(|key| unsafe {key == &*k}, h as u64);
-            if !spot.empty {
-                println!("NO FOUND {:?} with hash {}", unsafe { &*k }, h);
-            } else {
-                println!("found {:?} with hash {} at {:?}", unsafe { &*k }, h, spot);
-            }*/
-        });
-        self.table = new_table;
+impl<K: Eq + Hash, S, H: HashWord, A: Alloc + Clone + Default> Set<K, S, H, A> {
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_hasher_in(hash_builder, A::default())
     }
+}
 
-    #[inline(always)]
-    fn incr(&mut self) {
-        if self.table.size + 1 > self.table.capacity {
-            self.expand()
+impl<K: Eq + Hash, S, H: HashWord, A: Alloc + Clone> Set<K, S, H, A> {
+    /// Creates a set which allocates its tables with `alloc`. Growing the set
+    /// incrementally keeps two tables alive at once, so `A` is cloned for the new one.
+    pub fn with_hasher_in(hash_builder: S, alloc: A) -> Self {
+        let policy = Policy::default();
+        Set {
+            hash_builder,
+            table: error::unwrap(Table::new(2, alloc, &policy)),
+            old: None,
+            incremental: false,
+            policy,
+            marker: PhantomData,
         }
     }
 
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
     pub fn len(&self) -> usize {
-        self.table.size
+        self.table.size + self.old.as_ref().map_or(0, |old| old.table.size)
     }
 
-    #[inline(never)]
-    pub fn insert(&mut self, k: K) {
-        self.incr();
-        let hash = make_hash(&self.hash_builder, &k);
-        let spot = self.table.search_with::<K, _>(|key| key == &k, hash);
-        if spot.empty {
-            self.table.size += 1;
-            unsafe {
-                (*spot.group).size += 1;
+    /// Describes how the keys are spread over the table, see `Stats`.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::new(Group::<K, H>::entries());
+        self.table.add_stats(&mut stats);
+        if let Some(ref old) = self.old {
+            old.table.add_stats(&mut stats);
+        }
+        stats.len = self.len();
+        stats.capacity = self.capacity();
+        stats.finish();
+        stats
+    }
+
+    /// The number of keys the set can hold before it has to grow. Inserting
+    /// `capacity() - len()` new keys won't allocate.
+    pub fn capacity(&self) -> usize {
+        self.table.capacity
+    }
+
+    /// Checks the invariants of the tables and describes the first one which doesn't
+    /// hold. This is meant for finding bugs in `Set` itself. With the `validate`
+    /// feature it runs after every change.
+    pub fn validate(&self) -> Result<(), String> {
+        self.table.validate_groups(false)?;
+        if let Some(ref old) = self.old {
+            old.table.validate_groups(true).map_err(|err| format!("old table: {}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Panics if `validate` fails, with the `validate` feature.
+    #[inline(always)]
+    fn validate_change(&self) {
+        if cfg!(feature = "validate") {
+            if let Err(err) = self.validate() {
+                panic!("invalid set: {}", err);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<K, H> {
+        Iter {
+            raw: self.table.raw_iter(),
+            old: self.old.as_ref().map_or(RawIter::empty(), |old| old.table.raw_iter()),
+            marker: PhantomData,
+        }
+    }
+
+    /// Removes all keys from the set, returning them in an iterator.
+    /// The set is empty once the iterator is dropped, even if it wasn't consumed.
+    pub fn drain(&mut self) -> Drain<K, H, A> {
+        let old = self.old.take();
+        // The table always has an empty slot, so some group isn't full
+        let group_idx = (0..(self.table.group_mask + 1)).find(|&i| unsafe {
+            (*self.table.group(i)).search_for_empty().is_some()
+        }).unwrap();
+        Drain {
+            group_idx,
+            group: ptr::null(),
+            taken: 0,
+            old_raw: old.as_ref().map_or(RawIter::empty(), |old| old.table.raw_iter()),
+            table: &mut self.table,
+            old,
+            marker: PhantomData,
+        }
+    }
+
+    /// Makes the set grow incrementally. Instead of moving all the keys into the
+    /// larger table at once, the old table is kept around and a few of its groups
+    /// are moved on every following insertion or removal. Lookups check both tables
+    /// until that is done. This avoids long pauses when large sets grow, at the
+    /// cost of slightly slower operations while a resize is in progress.
+    pub fn set_incremental_resize(&mut self, incremental: bool) {
+        self.incremental = incremental;
+        if !incremental {
+            self.finish_migration();
+        }
+    }
+
+    /// Looks for a key in the table and then in the table we're migrating away from, if any.
+    /// Returns the spot and whether it is in the old table. Empty spots are always in the new table.
+    #[inline(always)]
+    fn search<F: FnMut(&K) -> bool>(&self, mut eq: F, hash: H) -> (RawEntry<K, H>, bool) {
+        let spot = self.table.lookup(&mut eq, hash);
+        if spot.empty {
+            if let Some(ref old) = self.old {
+                let old_spot = old.table.lookup(&mut eq, hash);
+                if !old_spot.empty {
+                    return (old_spot, true);
+                }
+            }
+        }
+        (spot, false)
+    }
+
+    /// Moves up to `groups` groups from the old table into the new one.
+    #[inline(never)]
+    #[cold]
+    fn migrate(&mut self, groups: usize) {
+        let done = match self.old {
+            Some(ref mut old) => {
+                let end = cmp::min(old.next + groups, old.table.group_mask + 1);
+                for i in old.next..end {
+                    unsafe {
+                        old.table.migrate_group(i, &mut self.table);
+                    }
+                }
+                old.next = end;
+                old.table.size == 0
+            }
+            None => false,
+        };
+        if done {
+            self.old = None;
+        }
+    }
+
+    fn finish_migration(&mut self) {
+        let groups = self.old.as_ref().map_or(0, |old| old.table.group_mask + 1);
+        self.migrate(groups);
+        self.validate_change();
+    }
+}
+
+pub struct Iter<'a, K: 'a, H: HashWord = u32> {
+    raw: RawIter<K, H>,
+    old: RawIter<K, H>,
+    marker: PhantomData<&'a K>,
+}
+
+impl<'a, K, H: HashWord> Iter<'a, K, H> {
+    #[inline]
+    fn next_hashed(&mut self) -> Option<(H, &'a K)> {
+        unsafe {
+            self.raw.next_hashed().or_else(|| self.old.next_hashed()).map(|(h, v)| (h, &*v))
+        }
+    }
+}
+
+impl<'a, K, H: HashWord> Iterator for Iter<'a, K, H> {
+    type Item = &'a K;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        self.next_hashed().map(|(_, v)| v)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.raw.remaining + self.old.remaining;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, K, H: HashWord> ExactSizeIterator for Iter<'a, K, H> {}
+
+pub struct IntoIter<K, H: HashWord = u32, A: Alloc + Clone = Global> {
+    raw: RawIter<K, H>,
+    old_raw: RawIter<K, H>,
+    // Keeps the groups alive until the iterator is dropped
+    #[allow(dead_code)]
+    table: Table<K, H, A>,
+    #[allow(dead_code)]
+    old: Option<Migration<K, H, A>>,
+    marker: PhantomData<K>,
+}
+
+impl<K, H: HashWord, A: Alloc + Clone> Iterator for IntoIter<K, H, A> {
+    type Item = K;
+
+    #[inline]
+    fn next(&mut self) -> Option<K> {
+        unsafe {
+            self.raw.next().or_else(|| self.old_raw.next()).map(|v| ptr::read(v))
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.raw.remaining + self.old_raw.remaining;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<K, H: HashWord, A: Alloc + Clone> ExactSizeIterator for IntoIter<K, H, A> {}
+
+impl<K, H: HashWord, A: Alloc + Clone> Drop for IntoIter<K, H, A> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+/// Groups are emptied one at a time, walking backwards from a group which isn't full,
+/// like `map::Drain` does. The keys left in the table can still be found, so if the
+/// iterator is leaked the set keeps them. Only the keys of the current group and of
+/// the table we were migrating away from are leaked with it.
+pub struct Drain<'a, K: 'a, H: HashWord = u32, A: Alloc + Clone + 'a = Global> {
+    table: &'a mut Table<K, H, A>,
+    group_idx: usize,
+    /// The group we're taking keys from, which is already empty in the table, and
+    /// how many of its keys are left.
+    group: *const Group<K, H>,
+    taken: usize,
+    old_raw: RawIter<K, H>,
+    // The table we were migrating away from is freed when the iterator is dropped
+    #[allow(dead_code)]
+    old: Option<Migration<K, H, A>>,
+    marker: PhantomData<K>,
+}
+
+impl<'a, K, H: HashWord, A: Alloc + Clone> Iterator for Drain<'a, K, H, A> {
+    type Item = K;
+
+    #[inline]
+    fn next(&mut self) -> Option<K> {
+        unsafe {
+            while self.taken == 0 {
+                if self.table.size == 0 {
+                    return self.old_raw.next().map(|v| ptr::read(v));
+                }
+                let group = &mut *self.table.group(self.group_idx);
+                // The new table has no tombstones, so the keys are `0..size`
+                self.taken = group.size as usize;
+                self.table.size -= self.taken;
+                group.clear();
+                self.group = group;
+                self.group_idx = self.group_idx.wrapping_sub(1) & self.table.group_mask;
+            }
+            self.taken -= 1;
+            Some(ptr::read((*self.group).value(self.taken)))
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.table.size + self.taken + self.old_raw.remaining;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, K, H: HashWord, A: Alloc + Clone> ExactSizeIterator for Drain<'a, K, H, A> {}
+
+impl<'a, K, H: HashWord, A: Alloc + Clone> Drop for Drain<'a, K, H, A> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+        self.table.validate_change(false);
+    }
+}
+
+impl<'a, K: Eq + Hash, S, H: HashWord, A: Alloc + Clone> IntoIterator for &'a Set<K, S, H, A> {
+    type Item = &'a K;
+    type IntoIter = Iter<'a, K, H>;
+
+    fn into_iter(self) -> Iter<'a, K, H> {
+        self.iter()
+    }
+}
+
+impl<K: Eq + Hash, S, H: HashWord, A: Alloc + Clone> IntoIterator for Set<K, S, H, A> {
+    type Item = K;
+    type IntoIter = IntoIter<K, H, A>;
+
+    fn into_iter(self) -> IntoIter<K, H, A> {
+        // `Set` drops the keys when it is dropped, so we move the tables out
+        // without running that and leave the keys to the iterator
+        let mut set = mem::ManuallyDrop::new(self);
+        unsafe {
+            ptr::drop_in_place(&mut set.hash_builder);
+            let table = ptr::read(&set.table);
+            let old = ptr::read(&set.old);
+            IntoIter {
+                raw: table.raw_iter(),
+                old_raw: old.as_ref().map_or(RawIter::empty(), |old| old.table.raw_iter()),
+                table,
+                old,
+                marker: PhantomData,
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash, S, H: HashWord, A: Alloc + Clone> Drop for Set<K, S, H, A> {
+    fn drop(&mut self) {
+        if mem::needs_drop::<K>() {
+            let mut old = self.old.as_ref().map_or(RawIter::empty(), |old| old.table.raw_iter());
+            let mut raw = self.table.raw_iter();
+            unsafe {
+                while let Some(k) = raw.next().or_else(|| old.next()) {
+                    ptr::drop_in_place(k as *mut K);
+                }
+            }
+        }
+    }
+}
+
+#[inline(never)]
+pub fn make_hash<T: ?Sized, S>(hash_state: &S, t: &T) -> u64
+    where T: Hash,
+          S: BuildHasher
+{
+    let mut state = hash_state.build_hasher();
+    t.hash(&mut state);
+    state.finish()
+}
+
+impl<K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Set<K, S, H, A> {
+    #[inline(never)]
+    #[cold]
+    fn try_expand(&mut self) -> Result<(), CollectionAllocErr> {
+        self.finish_migration();
+        if self.incremental {
+            let group_count = (self.table.group_mask + 1).checked_mul(1 << self.policy.growth_shift())
+                .ok_or(CollectionAllocErr::CapacityOverflow)?;
+            let new_table = Table::new(group_count, self.table.alloc.clone(), &self.policy)?;
+            let old = mem::replace(&mut self.table, new_table);
+            self.old = Some(Migration {
+                table: old,
+                next: 0,
+            });
+            Ok(())
+        } else {
+            unsafe {
+                self.table.grow_in_place(&self.policy)
+            }
+        }
+    }
+
+    /// Moves all keys into a new table with `group_count` groups.
+    fn try_resize(&mut self, group_count: usize) -> Result<(), CollectionAllocErr> {
+        self.finish_migration();
+        let mut new_table = Table::new(group_count, self.table.alloc.clone(), &self.policy)?;
+        for i in 0..(self.table.group_mask + 1) {
+            unsafe {
+                self.table.migrate_group(i, &mut new_table);
+            }
+        }
+        self.table = new_table;
+        Ok(())
+    }
+
+    /// Makes room for `additional` more keys, so they can be inserted without
+    /// growing the table again. This is cheaper than letting the set double
+    /// repeatedly during a bulk load.
+    pub fn reserve(&mut self, additional: usize) {
+        error::unwrap(self.try_reserve(additional))
+    }
+
+    /// Like `reserve`, but returns an error instead of panicking. If this fails the set
+    /// is left as it was, though it may already have finished an incremental resize.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), CollectionAllocErr> {
+        let needed = self.len().checked_add(additional).ok_or(CollectionAllocErr::CapacityOverflow)?;
+        if needed <= self.table.capacity {
+            return Ok(());
+        }
+        let group_count = Table::<K, H, A>::group_count_for(needed, &self.policy)?;
+        let result = if group_count == (self.table.group_mask + 1) << self.policy.growth_shift() {
+            // A single step grows the way inserting would
+            self.try_expand()
+        } else {
+            self.try_resize(group_count)
+        };
+        self.validate_change();
+        result
+    }
+
+    /// Shrinks the table as much as possible while keeping room for `min_capacity` keys,
+    /// or for the keys in the set if there are more. This also finishes an incremental
+    /// resize, freeing the old table. The set is left as it is if the smaller table
+    /// can't be allocated.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.finish_migration();
+        let needed = cmp::max(self.len(), min_capacity);
+        if let Ok(group_count) = Table::<K, H, A>::group_count_for(needed, &self.policy) {
+            if group_count < self.table.group_mask + 1 {
+                let _ = self.try_resize(group_count);
+            }
+        }
+        self.validate_change();
+    }
+
+    /// Shrinks the table as much as possible while keeping the keys in the set.
+    pub fn shrink_to_fit(&mut self) {
+        self.shrink_to(0);
+    }
+
+    /// Changes when the table grows and by how much. If the set holds more keys
+    /// than the table allows under the new policy, it grows right away.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.table.capacity = Table::<K, H, A>::capacity(self.table.group_mask + 1, &policy);
+        self.reserve(0);
+    }
+
+    #[inline(always)]
+    fn incr(&mut self) {
+        error::unwrap(self.try_incr())
+    }
+
+    #[inline(always)]
+    fn try_incr(&mut self) -> Result<(), CollectionAllocErr> {
+        if self.old.is_some() {
+            self.migrate(MIGRATE_GROUPS);
+        }
+        if self.len() + 1 > self.table.capacity {
+            self.try_expand()?;
+        }
+        Ok(())
+    }
+
+    #[inline(never)]
+    pub fn insert(&mut self, k: K) {
+        let hash = H::new(make_hash(&self.hash_builder, &k));
+        self.insert_hashed(hash, k);
+    }
+
+    /// Like `insert`, but returns an error instead of panicking if the table
+    /// has to grow and can't. The keys in the set are left as they were in that case.
+    pub fn try_insert(&mut self, k: K) -> Result<(), CollectionAllocErr> {
+        let hash = H::new(make_hash(&self.hash_builder, &k));
+        self.try_insert_hashed(hash, k)
+    }
+
+    #[inline(always)]
+    fn insert_hashed(&mut self, hash: H, k: K) {
+        error::unwrap(self.try_insert_hashed(hash, k))
+    }
+
+    #[inline(always)]
+    fn try_insert_hashed(&mut self, hash: H, k: K) -> Result<(), CollectionAllocErr> {
+        self.try_incr()?;
+        let (spot, _) = self.search(|key| key == &k, hash);
+        if spot.empty {
+            self.table.size += 1;
+            unsafe {
+                (*spot.group).size += 1;
             }
         }
-        //println!("inserting {:?} with hash {} at {:?}", unsafe { &k }, hash as u32, spot);
         unsafe {
-            (*spot.group).set(spot.pos, hash as u32, *(&k as *const _ as *const u64));
+            (*spot.group).set(spot.pos, hash, k);
         }
+        self.validate_change();
+        Ok(())
     }
 
     pub fn intern(&mut self, k: K) -> &K {
         self.incr();
-        let hash = make_hash(&self.hash_builder, &k);
-        let spot = self.table.search_with::<K, _>(|key| key == &k, hash);
+        let hash = H::new(make_hash(&self.hash_builder, &k));
+        let (spot, _) = self.search(|key| key == &k, hash);
         unsafe {
             if spot.empty {
                 self.table.size += 1;
                 (*spot.group).size += 1;
-                (*spot.group).set(spot.pos, hash as u32, *(&k as *const _ as *const u64));
+                (*spot.group).set(spot.pos, hash, k);
+                self.validate_change();
             }
-            &*((*spot.group).values.get_unchecked(spot.pos) as *const _ as *const K)
+            (*spot.group).value(spot.pos)
         }
     }
 
@@ -423,19 +1276,1440 @@ impl<K: Eq + Hash + Debug + Copy, S: BuildHasher> Set<K, S> {
         where K: Borrow<Q>,
               Q: Hash + Eq
     {
-        let hash = make_hash(&self.hash_builder, value);
-        let spot = self.table.search_with::<K, _>(|k| value.eq(k.borrow()), hash);
+        let hash = H::new(make_hash(&self.hash_builder, value));
+        let (spot, _) = self.search(|k| value.eq(k.borrow()), hash);
+        if spot.empty {
+            None
+        } else {
+            unsafe {
+                Some((*spot.group).value(spot.pos))
+            }
+        }
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, value: &Q) -> bool
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        self.take(value).is_some()
+    }
+
+    pub fn take<Q: ?Sized>(&mut self, value: &Q) -> Option<K>
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        let hash = H::new(make_hash(&self.hash_builder, value));
+        self.take_hashed(|k| value.eq(k.borrow()), hash)
+    }
+
+    fn take_hashed<F: FnMut(&K) -> bool>(&mut self, eq: F, hash: H) -> Option<K> {
+        if self.old.is_some() {
+            self.migrate(MIGRATE_GROUPS);
+        }
+        let (spot, in_old) = self.search(eq, hash);
+        if spot.empty {
+            None
+        } else {
+            let value = unsafe {
+                if in_old {
+                    self.old.as_mut().unwrap().table.tombstone(spot.group, spot.pos)
+                } else {
+                    self.table.remove(spot.group, spot.pos)
+                }
+            };
+            self.validate_change();
+            Some(value)
+        }
+    }
+
+    /// Creates a raw entry builder for looking up keys by a precomputed hash
+    /// or with a custom equality function.
+    pub fn raw_entry(&self) -> RawEntryBuilder<K, S, H, A> {
+        RawEntryBuilder { set: self }
+    }
+
+    /// Creates a raw entry builder which can insert into vacant entries without
+    /// hashing the key again. Room for the key is only made when it is inserted,
+    /// so lookups never grow the table.
+    pub fn raw_entry_mut(&mut self) -> RawEntryBuilderMut<K, S, H, A> {
+        RawEntryBuilderMut { set: self }
+    }
+}
+
+// The hashes passed to the raw entry API can either come from `make_hash` or
+// straight from a hasher built by `Set::hasher`, which give the same result.
+// We convert them with `HashWord::new` before using them.
+
+pub struct RawEntryBuilder<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    set: &'a Set<K, S, H, A>,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> RawEntryBuilder<'a, K, S, H, A> {
+    pub fn from_key<Q: ?Sized>(self, k: &Q) -> Option<&'a K>
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        let hash = make_hash(&self.set.hash_builder, k);
+        self.from_key_hashed_nocheck(hash, k)
+    }
+
+    pub fn from_key_hashed_nocheck<Q: ?Sized>(self, hash: u64, k: &Q) -> Option<&'a K>
+        where K: Borrow<Q>,
+              Q: Eq
+    {
+        self.from_hash(hash, |key| k.eq(key.borrow()))
+    }
+
+    pub fn from_hash<F: FnMut(&K) -> bool>(self, hash: u64, is_match: F) -> Option<&'a K> {
+        let (spot, _) = self.set.search(is_match, H::new(hash));
         if spot.empty {
             None
         } else {
             unsafe {
-                Some(&*((*spot.group).values.get_unchecked(spot.pos) as *const _ as *const K))
+                Some((*spot.group).value(spot.pos))
             }
         }
     }
 }
 
-#[inline(never)]
-pub fn intern_str(map: &mut Set<&'static &'static str, BuildHasherDefault<fx::FxHasher2>>, string: &'static &'static str) -> &'static &'static str {
-    map.intern(string)
+pub struct RawEntryBuilderMut<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    set: &'a mut Set<K, S, H, A>,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> RawEntryBuilderMut<'a, K, S, H, A> {
+    pub fn from_key<Q: ?Sized>(self, k: &Q) -> RawEntryMut<'a, K, S, H, A>
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        let hash = make_hash(&self.set.hash_builder, k);
+        self.from_key_hashed_nocheck(hash, k)
+    }
+
+    pub fn from_key_hashed_nocheck<Q: ?Sized>(self, hash: u64, k: &Q) -> RawEntryMut<'a, K, S, H, A>
+        where K: Borrow<Q>,
+              Q: Eq
+    {
+        self.from_hash(hash, |key| k.eq(key.borrow()))
+    }
+
+    pub fn from_hash<F: FnMut(&K) -> bool>(self, hash: u64, is_match: F) -> RawEntryMut<'a, K, S, H, A> {
+        let hash = H::new(hash);
+        let (spot, in_old) = self.set.search(is_match, hash);
+        if spot.empty {
+            RawEntryMut::Vacant(RawVacantEntryMut {
+                set: self.set,
+                spot,
+                hash,
+            })
+        } else if in_old {
+            RawEntryMut::Occupied(RawOccupiedEntryMut {
+                table: &mut self.set.old.as_mut().unwrap().table,
+                spot,
+                in_old,
+            })
+        } else {
+            RawEntryMut::Occupied(RawOccupiedEntryMut {
+                table: &mut self.set.table,
+                spot,
+                in_old,
+            })
+        }
+    }
+}
+
+pub enum RawEntryMut<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    Occupied(RawOccupiedEntryMut<'a, K, H, A>),
+    Vacant(RawVacantEntryMut<'a, K, S, H, A>),
+}
+
+pub struct RawOccupiedEntryMut<'a, K: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    table: &'a mut Table<K, H, A>,
+    spot: RawEntry<K, H>,
+    // The entry is in the table we're migrating away from
+    in_old: bool,
+}
+
+impl<'a, K, H: HashWord, A: Alloc + Clone> RawOccupiedEntryMut<'a, K, H, A> {
+    pub fn key(&self) -> &K {
+        unsafe {
+            (*self.spot.group).value(self.spot.pos)
+        }
+    }
+
+    pub fn into_key(self) -> &'a K {
+        unsafe {
+            (*self.spot.group).value(self.spot.pos)
+        }
+    }
+
+    pub fn remove(self) -> K {
+        let value = unsafe {
+            if self.in_old {
+                self.table.tombstone(self.spot.group, self.spot.pos)
+            } else {
+                self.table.remove(self.spot.group, self.spot.pos)
+            }
+        };
+        self.table.validate_change(self.in_old);
+        value
+    }
+}
+
+pub struct RawVacantEntryMut<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    set: &'a mut Set<K, S, H, A>,
+    spot: RawEntry<K, H>,
+    hash: H,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> RawVacantEntryMut<'a, K, S, H, A> {
+    pub fn insert(self, key: K) -> &'a K {
+        let hash = make_hash(&self.set.hash_builder, &key);
+        self.insert_hashed_nocheck(hash, key)
+    }
+
+    /// Inserts `key` with the given hash. If it's the hash the entry was looked up
+    /// with and the set didn't have to make room first, the slot found by the
+    /// lookup is used directly.
+    pub fn insert_hashed_nocheck(self, hash: u64, key: K) -> &'a K {
+        let hash = H::new(hash);
+        let set = self.set;
+        // Growing the table or moving groups of an incremental resize can
+        // fill the slot we found or move it elsewhere
+        let moved = set.old.is_some() || set.len() + 1 > set.table.capacity;
+        set.incr();
+        let spot = if hash == self.hash && !moved {
+            self.spot
+        } else {
+            set.table.search_for_empty(hash.get())
+        };
+        unsafe {
+            set.table.size += 1;
+            (*spot.group).size += 1;
+            (*spot.group).set(spot.pos, hash, key);
+            set.validate_change();
+            (*spot.group).value(spot.pos)
+        }
+    }
+}
+
+// Set algebra. Each key we look up in the other set comes with the hash stored for
+// it, which we reuse instead of hashing the key again if both sets hash keys the same way.
+
+/// Tells set algebra whether two hashers give the same hash for every key, so the
+/// hashes stored in one set can be used to look up keys in the other. Only return true
+/// if that is certain, since the lookups miss keys otherwise. Hashers which are seeded
+/// per instance can compare their seeds.
+pub trait SameHashes {
+    fn same_hashes(&self, other: &Self) -> bool;
+}
+
+/// Hashers built by `Default` all start out the same.
+impl<T: Hasher + Default> SameHashes for BuildHasherDefault<T> {
+    fn same_hashes(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// The keys of a `RandomState` are private, so we can't tell if two are the same.
+impl SameHashes for RandomState {
+    fn same_hashes(&self, _: &Self) -> bool {
+        false
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Set<K, S, H, A> {
+    /// Returns true if `k`, stored in another set with `hash`, is in `self`.
+    #[inline(always)]
+    fn contains_from(&self, k: &K, hash: H, same_hasher: bool) -> bool {
+        let hash = if same_hasher {
+            hash
+        } else {
+            H::new(make_hash(&self.hash_builder, k))
+        };
+        !self.search(|key| key == k, hash).0.empty
+    }
+
+    /// Like `difference`, but `same_hasher` says whether stored hashes can be reused.
+    fn difference_from<'a>(&'a self, other: &'a Set<K, S, H, A>, same_hasher: bool) -> Difference<'a, K, S, H, A> {
+        Difference {
+            iter: self.iter(),
+            other,
+            same_hasher,
+        }
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher + SameHashes, H: HashWord, A: Alloc + Clone> Set<K, S, H, A> {
+    /// Returns true if `self` and `other` give the same hashes, see `SameHashes`.
+    fn same_hasher(&self, other: &Set<K, S, H, A>) -> bool {
+        ptr::eq(self, other) || self.hash_builder.same_hashes(&other.hash_builder)
+    }
+
+    /// Visits the keys in `self` and then the keys in `other` which aren't in `self`.
+    pub fn union<'a>(&'a self, other: &'a Set<K, S, H, A>) -> Union<'a, K, S, H, A> {
+        Union {
+            iter: self.iter(),
+            rest: other.difference(self),
+        }
+    }
+
+    /// Visits the keys in both `self` and `other`.
+    pub fn intersection<'a>(&'a self, other: &'a Set<K, S, H, A>) -> Intersection<'a, K, S, H, A> {
+        // Look up the keys of the smaller set in the larger one
+        let (small, large) = if self.len() <= other.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Intersection {
+            iter: small.iter(),
+            other: large,
+            same_hasher: self.same_hasher(other),
+        }
+    }
+
+    /// Visits the keys in `self` which aren't in `other`.
+    pub fn difference<'a>(&'a self, other: &'a Set<K, S, H, A>) -> Difference<'a, K, S, H, A> {
+        self.difference_from(other, self.same_hasher(other))
+    }
+
+    /// Visits the keys which are in either `self` or `other`, but not both.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Set<K, S, H, A>) -> SymmetricDifference<'a, K, S, H, A> {
+        SymmetricDifference {
+            a: self.difference(other),
+            b: other.difference(self),
+        }
+    }
+
+    /// Returns true if all keys in `self` are in `other`.
+    pub fn is_subset(&self, other: &Set<K, S, H, A>) -> bool {
+        self.len() <= other.len() && self.difference(other).next().is_none()
+    }
+
+    /// Returns true if `self` and `other` have no keys in common.
+    pub fn is_disjoint(&self, other: &Set<K, S, H, A>) -> bool {
+        self.intersection(other).next().is_none()
+    }
+}
+
+impl<K: Eq + Hash + Clone, S: BuildHasher + SameHashes, H: HashWord, A: Alloc + Clone> Set<K, S, H, A> {
+    /// Inserts all keys in `other` into `self`.
+    pub fn union_with(&mut self, other: &Set<K, S, H, A>) {
+        let same_hasher = self.same_hasher(other);
+        let mut iter = other.iter();
+        while let Some((hash, k)) = iter.next_hashed() {
+            let hash = if same_hasher {
+                hash
+            } else {
+                H::new(make_hash(&self.hash_builder, k))
+            };
+            self.insert_hashed(hash, k.clone());
+        }
+    }
+
+    /// Removes the keys in `self` which aren't in `other`.
+    pub fn retain_in(&mut self, other: &Set<K, S, H, A>) {
+        let same_hasher = self.same_hasher(other);
+        // Removing keys moves others around, so find all of them before removing any
+        let remove: Vec<(H, K)> = {
+            let mut remove = Vec::new();
+            let mut iter = self.iter();
+            while let Some((hash, k)) = iter.next_hashed() {
+                if !other.contains_from(k, hash, same_hasher) {
+                    remove.push((hash, k.clone()));
+                }
+            }
+            remove
+        };
+        for (hash, k) in remove {
+            self.take_hashed(|key| *key == k, hash);
+        }
+    }
+}
+
+pub struct Intersection<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    iter: Iter<'a, K, H>,
+    other: &'a Set<K, S, H, A>,
+    same_hasher: bool,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Iterator for Intersection<'a, K, S, H, A> {
+    type Item = &'a K;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        while let Some((hash, k)) = self.iter.next_hashed() {
+            if self.other.contains_from(k, hash, self.same_hasher) {
+                return Some(k);
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.iter.len()))
+    }
+}
+
+pub struct Difference<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    iter: Iter<'a, K, H>,
+    other: &'a Set<K, S, H, A>,
+    same_hasher: bool,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Iterator for Difference<'a, K, S, H, A> {
+    type Item = &'a K;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        while let Some((hash, k)) = self.iter.next_hashed() {
+            if !self.other.contains_from(k, hash, self.same_hasher) {
+                return Some(k);
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.iter.len()))
+    }
+}
+
+pub struct Union<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    iter: Iter<'a, K, H>,
+    rest: Difference<'a, K, S, H, A>,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Iterator for Union<'a, K, S, H, A> {
+    type Item = &'a K;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next().or_else(|| self.rest.next())
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, rest) = self.rest.size_hint();
+        (self.iter.len(), rest.map(|rest| self.iter.len() + rest))
+    }
+}
+
+pub struct SymmetricDifference<'a, K: Eq + Hash + 'a, S: 'a, H: HashWord + 'a = u32, A: Alloc + Clone + 'a = Global> {
+    a: Difference<'a, K, S, H, A>,
+    b: Difference<'a, K, S, H, A>,
+}
+
+impl<'a, K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Iterator for SymmetricDifference<'a, K, S, H, A> {
+    type Item = &'a K;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        self.a.next().or_else(|| self.b.next())
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (_, a) = self.a.size_hint();
+        let (_, b) = self.b.size_hint();
+        (0, a.and_then(|a| b.map(|b| a + b)))
+    }
+}
+
+impl<K: Eq + Hash + Clone, S: Clone, H: HashWord, A: Alloc + Clone> Clone for Set<K, S, H, A> {
+    /// The groups are copied as they are, with their hashes, and then each key is cloned.
+    fn clone(&self) -> Self {
+        let clone_key = |from: &Group<K, H>, to: &mut Group<K, H>, pos: usize| unsafe {
+            ptr::write(to.values_mut().offset(pos as isize), from.value(pos).clone());
+        };
+        Set {
+            hash_builder: self.hash_builder.clone(),
+            table: self.table.clone_with(clone_key),
+            old: self.old.as_ref().map(|old| Migration {
+                table: old.table.clone_with(clone_key),
+                next: old.next,
+            }),
+            incremental: self.incremental,
+            policy: self.policy,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Debug, S, H: HashWord, A: Alloc + Clone> Debug for Set<K, S, H, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> PartialEq for Set<K, S, H, A> {
+    fn eq(&self, other: &Set<K, S, H, A>) -> bool {
+        self.len() == other.len() && self.difference_from(other, false).next().is_none()
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Eq for Set<K, S, H, A> {}
+
+impl<K: Eq + Hash, S: Default, H: HashWord, A: Alloc + Clone + Default> Default for Set<K, S, H, A> {
+    fn default() -> Self {
+        Set::new()
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher, H: HashWord, A: Alloc + Clone> Extend<K> for Set<K, S, H, A> {
+    fn extend<I: IntoIterator<Item = K>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for k in iter {
+            self.insert(k);
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone + 'a, S: BuildHasher, H: HashWord, A: Alloc + Clone> Extend<&'a K> for Set<K, S, H, A> {
+    fn extend<I: IntoIterator<Item = &'a K>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().cloned());
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher + Default, H: HashWord, A: Alloc + Clone + Default> FromIterator<K> for Set<K, S, H, A> {
+    fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> Self {
+        let mut set = Set::new();
+        set.extend(iter);
+        set
+    }
+}
+
+/// Keeps the hasher of the `HashSet`.
+impl<K: Eq + Hash, S: BuildHasher + Clone, H: HashWord, A: Alloc + Clone + Default> From<HashSet<K, S>> for Set<K, S, H, A> {
+    fn from(set: HashSet<K, S>) -> Self {
+        let mut result = Set::with_hasher(set.hasher().clone());
+        result.extend(set);
+        result
+    }
+}
+
+#[inline(never)]
+pub fn intern_str(map: &mut Set<&'static str, BuildHasherDefault<fx::FxHasher2>>, string: &'static str) -> &'static str {
+    map.intern(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::alloc::{AllocErr, Layout};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    type PlainSet = Set<u64, BuildHasherDefault<fx::PlainHasher>>;
+
+    fn check<S: BuildHasher, H: HashWord, A: Alloc + Clone>(set: &Set<u64, S, H, A>, model: &HashSet<u64>) -> bool {
+        set.validate().is_ok() &&
+            set.iter().count() == model.len() &&
+            set.len() == model.len() &&
+            model.iter().all(|k| set.get(k) == Some(k))
+    }
+
+    #[test]
+    fn remove_from_overflowed_chain() {
+        // With the identity hash, multiples of 1 << 20 all start probing at group 0
+        let mut set = PlainSet::new();
+        let mut model = HashSet::new();
+        for i in 1..40u64 {
+            set.insert(i << 20);
+            model.insert(i << 20);
+        }
+        for i in (1..40u64).filter(|i| i % 3 == 0) {
+            assert!(set.remove(&(i << 20)));
+            assert!(!set.remove(&(i << 20)));
+            model.remove(&(i << 20));
+            assert!(check(&set, &model));
+        }
+        assert_eq!(set.take(&(1 << 20)), Some(1 << 20));
+        model.remove(&(1 << 20));
+        assert!(check(&set, &model));
+    }
+
+    #[test]
+    fn remove_wrapped_chain() {
+        let mut set = PlainSet::new();
+        let mut model = HashSet::new();
+        // Fill the table up to just before it expands, so probing wraps around from
+        // the last group to group 0
+        let last = set.table.group_mask as u64;
+        while set.len() + 1 <= set.table.capacity {
+            let k = last + ((set.len() as u64 + 1) << 20);
+            set.insert(k);
+            model.insert(k);
+        }
+        for k in model.clone() {
+            assert!(set.remove(&k));
+            model.remove(&k);
+            assert!(check(&set, &model));
+        }
+    }
+
+    #[test]
+    fn grow_wrapped_chain() {
+        // Probing wraps around from the last group when the table grows in place,
+        // so the entries which ended up in the first groups have to be moved back
+        let mut set = PlainSet::new();
+        let mut model = HashSet::new();
+        for i in 1..200u64 {
+            let last = set.table.group_mask as u64;
+            let k = last + (i << 20);
+            set.insert(k);
+            model.insert(k);
+            assert!(check(&set, &model));
+        }
+        for i in 0..2000u64 {
+            set.insert(i * 7);
+            model.insert(i * 7);
+        }
+        assert!(check(&set, &model));
+    }
+
+    #[test]
+    fn grow_in_place_splits_groups() {
+        for shift in 1..4 {
+            let mut set = PlainSet::new();
+            set.set_policy(Policy::new(7, 8, shift));
+            let mut model = HashSet::new();
+            for i in 0..3000u64 {
+                // Runs of keys sharing a home group, some of them wrapping around
+                let last = set.table.group_mask as u64;
+                let k = match i % 3 {
+                    0 => last + (i << 20),
+                    1 => (i % 5) + (i << 20),
+                    _ => i * 13,
+                };
+                set.insert(k);
+                model.insert(k);
+                if i % 97 == 0 {
+                    assert!(check(&set, &model));
+                }
+            }
+            assert!(check(&set, &model));
+        }
+    }
+
+    #[test]
+    fn validate() {
+        // Keys with home group 0 fill it and overflow into group 1
+        let mut set = PlainSet::new();
+        let entries = Group::<u64, u32>::entries() as u64;
+        for i in 1..(entries + 3) {
+            set.insert(i << 20);
+        }
+        assert_eq!(set.validate(), Ok(()));
+        unsafe {
+            let group = &mut *set.table.group(0);
+            group.size -= 1;
+            assert_eq!(set.validate(), Err(format!("group 0: the hash at {} isn't zero, but the size is {}",
+                                                   entries - 1, entries - 1)));
+            let last = group.hash(entries as usize - 1);
+            *group.hashes_mut().offset(entries as isize - 1) = u32::zero();
+            assert_eq!(set.validate(), Err("group 1: the entry at 0 has home group 0, \
+                                            but group 0 before it isn't full".to_string()));
+            *group.hashes_mut().offset(entries as isize - 1) = last;
+            group.size += 1;
+        }
+        set.table.size += 1;
+        assert_eq!(set.validate(), Err(format!("the size is {}, but the groups hold {} entries",
+                                               entries + 3, entries + 2)));
+        set.table.size -= 1;
+        assert_eq!(set.validate(), Ok(()));
+    }
+
+    #[test]
+    fn hash64() {
+        fn eq_calls<H: HashWord>(incremental: bool) -> usize {
+            let mut set = Set::<u64, BuildHasherDefault<fx::PlainHasher>, H>::new();
+            set.set_incremental_resize(incremental);
+            let mut model = HashSet::new();
+            // These keys only differ in the upper 32 bits of their hashes
+            for i in 0..1000u64 {
+                set.insert(i << 32);
+                model.insert(i << 32);
+            }
+            assert!(check(&set, &model));
+            let mut calls = 0;
+            for i in 0..1000u64 {
+                let k = (i + 1000) << 32;
+                let found = set.raw_entry().from_hash(k, |key| {
+                    calls += 1;
+                    *key == k
+                });
+                assert_eq!(found, None);
+            }
+            calls
+        }
+        assert!(eq_calls::<u32>(false) > 1000);
+        assert_eq!(eq_calls::<u64>(false), 0);
+        assert_eq!(eq_calls::<u64>(true), 0);
+    }
+
+    #[test]
+    fn iterators() {
+        let mut set = PlainSet::new();
+        let mut model = HashSet::new();
+        for i in 1..100u64 {
+            set.insert(i * 3 << 20);
+            model.insert(i * 3 << 20);
+        }
+        set.remove(&(3 << 20));
+        model.remove(&(3 << 20));
+
+        let iter = set.iter();
+        assert_eq!(iter.len(), model.len());
+        assert_eq!(iter.cloned().collect::<HashSet<_>>(), model);
+        assert_eq!((&set).into_iter().count(), model.len());
+
+        let drained: HashSet<_> = set.drain().collect();
+        assert_eq!(drained, model);
+        assert!(check(&set, &HashSet::new()));
+
+        // Dropping a drain without consuming it still empties the set
+        set.insert(1);
+        set.insert(2);
+        set.drain().next();
+        assert_eq!(set.len(), 0);
+        assert_eq!(set.iter().next(), None);
+
+        for &k in &model {
+            set.insert(k);
+        }
+        let mut into_iter = set.into_iter();
+        assert_eq!(into_iter.len(), model.len());
+        into_iter.next();
+        assert_eq!(into_iter.len(), model.len() - 1);
+    }
+
+    #[test]
+    fn traits() {
+        let std: HashSet<u64> = (0..1000u64).map(|i| i * 3).collect();
+        let set: Set<u64> = std.iter().cloned().collect();
+        assert_eq!(Set::from(std.clone()), set);
+        assert!(check(&set, &std));
+
+        // Clones keep the table we're migrating away from
+        let mut incremental = Set::<u64>::new();
+        incremental.set_incremental_resize(true);
+        incremental.extend(&[1, 2, 3]);
+        while incremental.old.is_none() {
+            let k = incremental.len() as u64 * 3;
+            incremental.insert(k);
+        }
+        let clone = incremental.clone();
+        assert!(clone.old.is_some());
+        assert_eq!(clone, incremental);
+        incremental.insert(1000);
+        assert!(clone != incremental);
+
+        // Keys which aren't `Copy` are cloned one by one, skipping the ones migrated
+        // out of the old table
+        let mut strings = Set::<String>::new();
+        strings.set_incremental_resize(true);
+        while strings.old.is_none() {
+            let k = strings.len().to_string();
+            strings.insert(k);
+        }
+        strings.remove(&"0".to_string());
+        let clone = strings.clone();
+        assert_eq!(clone, strings);
+        assert_eq!(clone.validate(), Ok(()));
+        drop(strings);
+        assert!(clone.get(&"1".to_string()).is_some());
+
+        let one: Set<u64> = Some(7).into_iter().collect();
+        assert_eq!(format!("{:?}", one), "{7}");
+        assert_eq!(Set::<u64>::default().len(), 0);
+    }
+
+    #[test]
+    fn group_layout() {
+        assert_eq!((Group::<u32, u32>::entries(), Group::<u32, u32>::stride()), (7, 64));
+        assert_eq!((Group::<u64, u32>::entries(), Group::<u64, u32>::stride()), (5, 64));
+        assert_eq!((Group::<&str, u32>::entries(), Group::<&str, u32>::stride()), (3, 64));
+        assert_eq!((Group::<[u64; 3], u32>::entries(), Group::<[u64; 3], u32>::stride()), (2, 64));
+        assert_eq!((Group::<[u64; 12], u32>::entries(), Group::<[u64; 12], u32>::stride()), (1, 128));
+        assert_eq!(Group::<u64, u32>::values_offset(5), 24);
+
+        assert_eq!((Group::<u32, u64>::entries(), Group::<u32, u64>::stride()), (4, 64));
+        assert_eq!((Group::<u64, u64>::entries(), Group::<u64, u64>::stride()), (3, 64));
+        assert_eq!((Group::<&str, u64>::entries(), Group::<&str, u64>::stride()), (2, 64));
+        assert_eq!((Group::<[u64; 12], u64>::entries(), Group::<[u64; 12], u64>::stride()), (1, 128));
+        assert_eq!(Group::<u64, u64>::values_offset(3), 32);
+    }
+
+    fn key_sizes<K: Eq + Hash + Debug + Copy, H: HashWord>(keys: &[K]) {
+        let mut set = Set::<K, BuildHasherDefault<fx::FxHasher>, H>::new();
+        let mut model = HashSet::new();
+        for k in keys {
+            assert_eq!(*set.intern(*k), *k);
+            model.insert(*k);
+        }
+        assert_eq!(set.len(), model.len());
+        for k in keys.iter().step_by(2) {
+            assert_eq!(set.take(k), model.take(k));
+        }
+        assert_eq!(set.iter().cloned().collect::<HashSet<_>>(), model);
+        for k in keys {
+            assert_eq!(set.get(k), model.get(k));
+        }
+    }
+
+    #[test]
+    fn arbitrary_key_sizes() {
+        let strs: Vec<String> = (0..300).map(|i| format!("symbol{}", i)).collect();
+        let strs: Vec<&str> = strs.iter().map(|s| &**s).collect();
+        key_sizes::<_, u32>(&strs);
+        key_sizes::<_, u32>(&(0..300u32).collect::<Vec<_>>());
+        key_sizes::<_, u32>(&(0..300u64).map(|i| [i; 12]).collect::<Vec<_>>());
+        key_sizes::<_, u32>(&(0..300u64).map(|i| [i, i * 7, !i]).collect::<Vec<_>>());
+        key_sizes::<_, u64>(&strs);
+        key_sizes::<_, u64>(&(0..300u32).collect::<Vec<_>>());
+        key_sizes::<_, u64>(&(0..300u64).map(|i| [i; 12]).collect::<Vec<_>>());
+        key_sizes::<_, u64>(&(0..=255u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn raw_entry() {
+        let mut set = Set::<String, BuildHasherDefault<fx::FxHasher>>::new();
+        let mut allocated = 0;
+        let names: Vec<_> = (0..50).map(|i| format!("sym{}", i)).collect();
+        for i in 0..200 {
+            // Look up by a borrowed string and only allocate a key if it's missing
+            let name = &*names[i % 50];
+            let hash = make_hash(set.hasher(), name);
+            let interned = match set.raw_entry_mut().from_key_hashed_nocheck(hash, name) {
+                RawEntryMut::Occupied(e) => e.into_key(),
+                RawEntryMut::Vacant(e) => {
+                    allocated += 1;
+                    e.insert_hashed_nocheck(hash, name.to_string())
+                }
+            };
+            assert_eq!(interned, name);
+        }
+        assert_eq!(allocated, 50);
+        assert_eq!(set.len(), 50);
+
+        // A hash straight from the hasher is accepted too
+        let mut hasher = set.hasher().build_hasher();
+        "sym7".hash(&mut hasher);
+        assert_eq!(set.raw_entry().from_hash(hasher.finish(), |k| k == "sym7").map(|k| &**k), Some("sym7"));
+        assert_eq!(set.raw_entry().from_key("sym50"), None);
+
+        match set.raw_entry_mut().from_key("sym3") {
+            RawEntryMut::Occupied(e) => assert_eq!(e.remove(), "sym3"),
+            RawEntryMut::Vacant(_) => panic!(),
+        }
+        assert_eq!(set.get("sym3"), None);
+
+        // Inserting with another hash than the lookup used finds a new slot
+        match set.raw_entry_mut().from_hash(0, |_| false) {
+            RawEntryMut::Vacant(e) => assert_eq!(e.insert("new".to_string()), "new"),
+            RawEntryMut::Occupied(_) => panic!(),
+        }
+        assert_eq!(set.get("new").map(|k| &**k), Some("new"));
+        assert_eq!(set.len(), 50);
+        assert!(set.validate().is_ok());
+    }
+
+    #[test]
+    fn raw_entry_reserves_on_insert() {
+        for &incremental in &[false, true] {
+            let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>>::new();
+            set.set_incremental_resize(incremental);
+            let mut k = 0;
+            while set.len() < set.capacity() {
+                set.insert(k);
+                k += 1;
+            }
+            // The table is full, but looking up keys doesn't grow it
+            let capacity = set.capacity();
+            for i in 0..(k + 10) {
+                match set.raw_entry_mut().from_key(&i) {
+                    RawEntryMut::Occupied(e) => assert_eq!(*e.key(), i),
+                    RawEntryMut::Vacant(_) => assert!(i >= k),
+                }
+            }
+            assert_eq!(set.capacity(), capacity);
+
+            // Inserting grows it, and the key ends up where lookups find it
+            match set.raw_entry_mut().from_key(&k) {
+                RawEntryMut::Vacant(e) => assert_eq!(*e.insert(k), k),
+                RawEntryMut::Occupied(_) => panic!(),
+            }
+            assert!(set.capacity() > capacity);
+            assert!(set.validate().is_ok());
+            for i in 0..(k + 1) {
+                assert_eq!(set.get(&i), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn drops_keys() {
+        let count = Rc::new(());
+        let keys = |n: u64| (0..n).map(|i| (i, count.clone())).collect::<Vec<_>>();
+        {
+            let mut set = Set::<(u64, Rc<()>), BuildHasherDefault<fx::FxHasher>>::new();
+            set.set_incremental_resize(true);
+            // Stop while keys are still in the old table
+            for key in keys(300) {
+                set.insert(key);
+                if set.old.is_some() && set.len() > 100 {
+                    break;
+                }
+            }
+            assert!(set.old.is_some());
+            let len = set.len();
+            set.remove(&(5, count.clone()));
+            assert_eq!(Rc::strong_count(&count), len);
+        }
+        assert_eq!(Rc::strong_count(&count), 1);
+
+        let mut set = Set::<(u64, Rc<()>), BuildHasherDefault<fx::FxHasher>>::new();
+        set.extend(keys(100));
+        let mut iter = set.into_iter();
+        iter.next();
+        drop(iter);
+        assert_eq!(Rc::strong_count(&count), 1);
+
+        let mut set = Set::<(u64, Rc<()>), BuildHasherDefault<fx::FxHasher>>::new();
+        set.extend(keys(100));
+        set.drain().next();
+        assert_eq!(set.len(), 0);
+        assert_eq!(Rc::strong_count(&count), 1);
+
+        // A leaked drain leaks the keys it took out of the table, but the set doesn't
+        // drop them again
+        // Keys with the home group 0 make a long probe sequence
+        let mut set = Set::<(u64, Rc<()>), BuildHasherDefault<fx::PlainHasher>>::new();
+        set.extend(keys(100).into_iter().map(|(i, rc)| (if i % 2 == 0 { i << 20 } else { i }, rc)));
+        let mut drain = set.drain();
+        let drained: Vec<_> = drain.by_ref().take(60).collect();
+        let left = drain.len();
+        mem::forget(drain);
+        let leaked = left - set.len();
+        assert!(leaked < Group::<(u64, Rc<()>), u32>::entries());
+        assert_eq!(set.validate(), Ok(()));
+        assert!(set.iter().all(|k| set.get(k).is_some()));
+        assert!(drained.iter().all(|k| set.get(k).is_none()));
+        assert_eq!(Rc::strong_count(&count), 1 + 60 + left);
+        drop(drained);
+        drop(set);
+        assert_eq!(Rc::strong_count(&count), 1 + leaked);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn avx2_matches<K, H: HashWord>(group: &Group<K, H>, hash: H) -> u32 {
+        Avx2::matches(group, hash)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn matchers_agree<K: Eq + Hash + Debug + Copy, H: HashWord>(keys: &[K]) {
+        let mut set = Set::<K, BuildHasherDefault<fx::FxHasher>, H>::new();
+        for k in keys {
+            set.insert(*k);
+        }
+        let mut hashes = vec![H::new(0), H::new(0x8765_4321), H::new(0x8765_4321 << 32)];
+        for i in 0..(set.table.group_mask + 1) {
+            let group = unsafe { &*set.table.group(i) };
+            for j in 0..(group.size as usize) {
+                hashes.push(unsafe { group.hash(j) });
+            }
+        }
+        for i in 0..(set.table.group_mask + 1) {
+            let group = unsafe { &*set.table.group(i) };
+            for &h in &hashes {
+                let expected = unsafe { Scalar::matches(group, h) };
+                assert_eq!(unsafe { Sse2::matches(group, h) }, expected);
+                if simd::supported(simd::Level::Avx2) {
+                    assert_eq!(unsafe { avx2_matches(group, h) }, expected);
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn simd_matches_scalar() {
+        matchers_agree::<_, u32>(&(0..200u32).collect::<Vec<_>>());
+        matchers_agree::<_, u32>(&(0..200u64).collect::<Vec<_>>());
+        matchers_agree::<_, u32>(&(0..200u8).collect::<Vec<_>>());
+        matchers_agree::<_, u32>(&(0..200u64).map(|i| [i, !i]).collect::<Vec<_>>());
+        matchers_agree::<_, u32>(&[(), ()]);
+        matchers_agree::<_, u64>(&(0..200u32).collect::<Vec<_>>());
+        matchers_agree::<_, u64>(&(0..200u64).collect::<Vec<_>>());
+        matchers_agree::<_, u64>(&(0..200u8).collect::<Vec<_>>());
+        matchers_agree::<_, u64>(&(0..200u64).map(|i| [i, !i]).collect::<Vec<_>>());
+        matchers_agree::<_, u64>(&[(), ()]);
+    }
+
+    #[test]
+    fn incremental_resize() {
+        let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>>::new();
+        set.set_incremental_resize(true);
+        let mut model = HashSet::new();
+        let progress = |set: &Set<u64, _>| {
+            set.old.as_ref().map(|old| (old.table.group_mask, old.next))
+        };
+        for i in 0..3000u64 {
+            let before = progress(&set);
+            set.insert(i);
+            model.insert(i);
+            // Only a few groups are moved per insertion
+            if let (Some(before), Some(after)) = (before, progress(&set)) {
+                if before.0 == after.0 {
+                    assert!(after.1 - before.1 <= MIGRATE_GROUPS);
+                }
+            }
+            if i % 7 == 0 {
+                assert_eq!(set.take(&(i / 2)), model.take(&(i / 2)));
+            }
+            if i % 100 == 0 {
+                assert!(check(&set, &model));
+            }
+        }
+        assert!(check(&set, &model));
+
+        // Force a migration to be in progress, then check the iterators see both tables
+        while set.old.is_none() {
+            let k = model.len() as u64 * 1000;
+            set.insert(k);
+            model.insert(k);
+        }
+        assert!(set.old.as_ref().unwrap().table.size > 0);
+        assert_eq!(set.iter().len(), model.len());
+        assert_eq!(set.iter().cloned().collect::<HashSet<_>>(), model);
+
+        let old_key = unsafe {
+            *set.old.as_ref().unwrap().table.raw_iter().next().unwrap()
+        };
+        match set.raw_entry_mut().from_key(&old_key) {
+            RawEntryMut::Occupied(e) => assert_eq!(e.remove(), old_key),
+            RawEntryMut::Vacant(_) => panic!(),
+        }
+        model.remove(&old_key);
+        assert!(check(&set, &model));
+
+        let drained: HashSet<_> = set.drain().collect();
+        assert_eq!(drained, model);
+        assert!(set.old.is_none());
+        assert_eq!(set.len(), 0);
+
+        // Turning it off finishes the migration
+        for i in 0..3000u64 {
+            set.insert(i);
+        }
+        set.set_incremental_resize(false);
+        assert!(set.old.is_none());
+        assert_eq!(set.len(), 3000);
+        assert_eq!(set.into_iter().len(), 3000);
+    }
+
+    /// Counts the bytes allocated through it which haven't been freed yet.
+    #[derive(Clone, Default)]
+    struct Counting(Rc<Cell<usize>>);
+
+    unsafe impl Alloc for Counting {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+            self.0.set(self.0.get() + layout.size());
+            Global.alloc(layout)
+        }
+
+        unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.set(self.0.get() - layout.size());
+            Global.dealloc(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn custom_alloc() {
+        for &incremental in &[false, true] {
+            let live = Counting::default();
+            let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>, u32, _>::new_in(live.clone());
+            set.set_incremental_resize(incremental);
+            for i in 0..3000u64 {
+                set.insert(i);
+            }
+            let lines = (set.table.group_mask + 1) * (Group::<u64, u32>::stride() / 64);
+            let old = set.old.as_ref().map_or(0, |old| old.table.group_mask + 1);
+            assert_eq!(live.0.get(), (lines + old * Group::<u64, u32>::stride() / 64) * 64);
+            let clone = set.clone();
+            drop(set);
+            assert_eq!(clone.len(), 3000);
+            drop(clone);
+            assert_eq!(live.0.get(), 0);
+        }
+    }
+
+    #[test]
+    fn stats() {
+        fn stats<H: HashWord>(incremental: bool) -> Stats {
+            let mut set = Set::<u64, BuildHasherDefault<fx::PlainHasher>, H>::new();
+            set.set_incremental_resize(incremental);
+            // The first 20 keys only differ in the upper 32 bits of their hashes
+            for i in 0..20u64 {
+                set.insert(i << 32);
+            }
+            for i in 1..300u64 {
+                set.insert(i);
+            }
+            let stats = set.stats();
+            assert_eq!(stats.len, set.len());
+            assert_eq!(stats.capacity, set.capacity());
+            assert_eq!(stats.probe_lengths.iter().sum::<usize>(), set.len());
+            assert_eq!(stats.group_sizes.iter().sum::<usize>(), stats.groups);
+            assert_eq!(stats.memory, stats.groups * Group::<u64, H>::stride());
+            assert!(stats.load_factor > 0.0 && stats.load_factor < 1.0);
+            stats
+        }
+        for &incremental in &[false, true] {
+            let stats32 = stats::<u32>(incremental);
+            assert_eq!(stats32.tag_collisions, 20 * 19 / 2);
+            assert!(stats32.expected_tag_collisions < 1.0);
+            assert_eq!(stats::<u64>(incremental).tag_collisions, 0);
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let policies = [Policy::default(), Policy::new(1, 2, 1), Policy::new(7, 8, 2), Policy::new(1, 1, 3)];
+        for &policy in &policies {
+            for &incremental in &[false, true] {
+                let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>>::new();
+                set.set_incremental_resize(incremental);
+                set.set_policy(policy);
+                let mut model = HashSet::new();
+                let mut groups = set.table.group_mask + 1;
+                for i in 0..5000u64 {
+                    set.insert(i);
+                    model.insert(i);
+                    let slots = (set.table.group_mask + 1) * Group::<u64, u32>::entries();
+                    assert_eq!(set.capacity(), policy.capacity(slots));
+                    assert!(set.table.size < slots);
+                    if set.table.group_mask + 1 != groups {
+                        assert_eq!(set.table.group_mask + 1, groups << policy.growth_shift());
+                        groups = set.table.group_mask + 1;
+                    }
+                }
+                assert!(check(&set, &model));
+            }
+        }
+
+        // A lower load makes the table grow right away
+        let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>>::new();
+        let mut model = HashSet::new();
+        for i in 0..1000u64 {
+            set.insert(i);
+            model.insert(i);
+        }
+        let groups = set.table.group_mask + 1;
+        set.set_policy(Policy::new(1, 4, 1));
+        assert!(set.table.group_mask + 1 > groups && set.capacity() >= set.len());
+        assert!(check(&set, &model));
+    }
+
+    #[test]
+    fn reserve_and_shrink() {
+        for &s in &[0, 1, 7, 100, 1000] {
+            assert!(PlainSet::with_capacity(s).capacity() >= s);
+        }
+        for &incremental in &[false, true] {
+            let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>>::new();
+            set.set_incremental_resize(incremental);
+            let mut model = HashSet::new();
+            for i in 0..100u64 {
+                set.insert(i);
+                model.insert(i);
+            }
+            // Both a single doubling and a larger jump
+            for &additional in &[set.capacity() - set.len() + 1, 10000] {
+                set.reserve(additional);
+                assert!(set.capacity() - set.len() >= additional);
+                assert!(check(&set, &model));
+            }
+            let capacity = set.capacity();
+            let groups = set.table.group_mask;
+            for i in 100..10100u64 {
+                set.insert(i);
+                model.insert(i);
+            }
+            assert_eq!(set.table.group_mask, groups);
+            assert_eq!(set.capacity(), capacity);
+            assert!(check(&set, &model));
+
+            for i in 50..10100u64 {
+                assert!(set.remove(&i));
+                model.remove(&i);
+            }
+            set.shrink_to(1000);
+            assert!(set.capacity() >= 1000 && set.capacity() < capacity);
+            assert!(check(&set, &model));
+            set.shrink_to_fit();
+            assert!(set.capacity() >= 50 && set.capacity() < 1000);
+            assert!(set.old.is_none());
+            assert!(check(&set, &model));
+        }
+    }
+
+    /// Fails allocations larger than the given number of bytes.
+    #[derive(Clone)]
+    struct Limited(usize);
+
+    unsafe impl Alloc for Limited {
+        unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+            if layout.size() > self.0 {
+                return Err(AllocErr);
+            }
+            Global.alloc(layout)
+        }
+
+        unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+            Global.dealloc(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn fallible_alloc() {
+        assert_eq!(PlainSet::try_with_capacity(usize::max_value()).err(),
+                   Some(CollectionAllocErr::CapacityOverflow));
+        let mut set = PlainSet::new();
+        set.insert(1);
+        assert_eq!(set.try_reserve(usize::max_value()), Err(CollectionAllocErr::CapacityOverflow));
+
+        for &incremental in &[false, true] {
+            let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>, u32, _>::new_in(Limited(64 * 1024));
+            set.set_incremental_resize(incremental);
+            let mut model = HashSet::new();
+            let mut i = 0;
+            let err = loop {
+                match set.try_insert(i) {
+                    Ok(()) => model.insert(i),
+                    Err(err) => break err,
+                };
+                i += 1;
+            };
+            assert_eq!(err, CollectionAllocErr::AllocErr);
+            assert!(check(&set, &model));
+            assert_eq!(set.try_reserve(set.len()), Err(CollectionAllocErr::AllocErr));
+            assert!(check(&set, &model));
+        }
+    }
+
+    /// Hashes like `FxHasher`, with a seed mixed in first.
+    #[derive(Clone)]
+    struct Seeded(u64);
+
+    impl BuildHasher for Seeded {
+        type Hasher = fx::FxHasher;
+
+        fn build_hasher(&self) -> fx::FxHasher {
+            let mut hasher = fx::FxHasher::default();
+            hasher.write_u64(self.0);
+            hasher
+        }
+    }
+
+    impl SameHashes for Seeded {
+        fn same_hashes(&self, other: &Self) -> bool {
+            self.0 == other.0
+        }
+    }
+
+    /// A key which is neither `Copy` nor `Debug`.
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    struct Name(String);
+
+    #[test]
+    fn algebra_clone_keys() {
+        type Names = Set<Name, BuildHasherDefault<fx::FxHasher>>;
+        let names = |keys: ::std::ops::Range<u32>| keys.map(|i| Name(i.to_string())).collect::<Vec<_>>();
+        let mut a = Names::default();
+        a.extend(&names(0..50));
+        let b: Names = names(25..75).into_iter().collect();
+        let mut union = a.clone();
+        union.union_with(&b);
+        assert_eq!(union.len(), 75);
+        a.retain_in(&b);
+        assert_eq!(a.len(), 25);
+        assert!(names(25..50).iter().all(|k| a.get(k).is_some()));
+        assert_eq!(a.validate(), Ok(()));
+    }
+
+    #[test]
+    fn algebra_seeded_hashers() {
+        let build = |seed, keys: ::std::ops::Range<u64>| {
+            let mut set = Set::<u64, Seeded>::with_hasher(Seeded(seed));
+            set.extend(keys);
+            set
+        };
+        let a = build(1, 0..100);
+        for &seed in &[1, 2] {
+            let b = build(seed, 50..150);
+            assert_eq!(a.same_hasher(&b), seed == 1);
+            assert_eq!(a.intersection(&b).count(), 50);
+            assert_eq!(a.difference(&b).count(), 50);
+            assert_eq!(a.union(&b).count(), 150);
+            let mut union = a.clone();
+            union.union_with(&b);
+            assert_eq!(union.len(), 150);
+            assert!(union.validate().is_ok());
+            assert!((50..150).all(|k| union.get(&k).is_some()));
+        }
+    }
+
+    fn algebra<S: BuildHasher + SameHashes + Default>(a: &[u8], b: &[u8]) -> bool {
+        // Sets with incremental resizing can have keys in two tables
+        let build = |keys: &[u8], incremental: bool| {
+            let mut set = Set::<u8, S>::new();
+            set.set_incremental_resize(incremental);
+            for &k in keys {
+                set.insert(k);
+            }
+            set
+        };
+        let (set_a, set_b) = (build(a, true), build(b, false));
+        let model_a: HashSet<u8> = a.iter().cloned().collect();
+        let model_b: HashSet<u8> = b.iter().cloned().collect();
+        fn same<'a, I: Iterator<Item = &'a u8>, J: Iterator<Item = &'a u8>>(i: I, j: J) -> bool {
+            let i: Vec<_> = i.collect();
+            let j: HashSet<_> = j.collect();
+            i.len() == j.len() && i.into_iter().all(|k| j.contains(k))
+        }
+
+        let mut union = build(a, false);
+        union.union_with(&set_b);
+        let mut retained = build(a, true);
+        retained.retain_in(&set_b);
+
+        same(set_a.union(&set_b), model_a.union(&model_b)) &&
+            same(set_a.intersection(&set_b), model_a.intersection(&model_b)) &&
+            same(set_b.intersection(&set_a), model_b.intersection(&model_a)) &&
+            same(set_a.difference(&set_b), model_a.difference(&model_b)) &&
+            same(set_a.symmetric_difference(&set_b), model_a.symmetric_difference(&model_b)) &&
+            same(union.iter(), model_a.union(&model_b)) &&
+            same(retained.iter(), model_a.intersection(&model_b)) &&
+            set_a.is_subset(&set_b) == model_a.is_subset(&model_b) &&
+            set_a.is_disjoint(&set_b) == model_a.is_disjoint(&model_b) &&
+            union.is_subset(&set_a) == model_b.is_subset(&model_a) &&
+            set_a.is_subset(&union)
+    }
+
+    /// Turns a key from quickcheck into one of a few shapes which stress the table
+    /// when hashing is the identity.
+    fn shaped_key(shape: u8, k: u16, group_mask: usize) -> u64 {
+        let k = k as u64 % 64;
+        match shape % 4 {
+            // Few keys, so most operations hit an existing one
+            0 => k,
+            // Keys with home group 0, which make long probe chains
+            1 => k << 20,
+            // Keys with the last group as home, so probing wraps around to group 0
+            2 => group_mask as u64 + (k << 20),
+            _ => k * 0x9e37_79b9,
+        }
+    }
+
+    /// Runs a sequence of operations on a `Set` and a `HashSet` and compares them after each one.
+    fn ops_match_std<H: HashWord>(ops: &[(u8, u8, u16)], incremental: bool) -> bool {
+        let mut set = Set::<u64, BuildHasherDefault<fx::PlainHasher>, H>::new();
+        set.set_incremental_resize(incremental);
+        let mut model = HashSet::new();
+        for &(op, shape, k) in ops {
+            let k = shaped_key(shape, k, set.table.group_mask);
+            let ok = match op % 9 {
+                0 | 1 => {
+                    set.insert(k);
+                    model.insert(k);
+                    true
+                }
+                2 => {
+                    model.insert(k);
+                    *set.intern(k) == k
+                }
+                3 => set.get(&k) == model.get(&k),
+                4 => set.remove(&k) == model.remove(&k),
+                5 => set.take(&k) == model.take(&k),
+                6 => {
+                    // Right up to or just past the point where the table grows
+                    let room = set.capacity() - set.len();
+                    set.reserve(room + (k as usize & 1));
+                    set.capacity() >= model.len() + room + (k as usize & 1)
+                }
+                7 => {
+                    set.shrink_to(k as usize % 128);
+                    set.capacity() >= model.len()
+                }
+                _ => {
+                    let mut drained: Vec<u64> = set.drain().collect();
+                    drained.sort();
+                    let mut expected: Vec<u64> = model.drain().collect();
+                    expected.sort();
+                    drained == expected
+                }
+            };
+            if !ok || !check(&set, &model) {
+                return false;
+            }
+        }
+        true
+    }
+
+    quickcheck! {
+        fn ops_match_std_32(ops: Vec<(u8, u8, u16)>, incremental: bool) -> bool {
+            ops_match_std::<u32>(&ops, incremental)
+        }
+
+        fn ops_match_std_64(ops: Vec<(u8, u8, u16)>, incremental: bool) -> bool {
+            ops_match_std::<u64>(&ops, incremental)
+        }
+
+        fn algebra_matches_std(a: Vec<u8>, b: Vec<u8>) -> bool {
+            // With a `RandomState` the sets hash differently, so keys are hashed again
+            algebra::<BuildHasherDefault<fx::FxHasher>>(&a, &b) && algebra::<RandomState>(&a, &b)
+        }
+
+        fn remove_matches_std(ops: Vec<(bool, u8, u8)>, incremental: bool) -> bool {
+            let mut set = PlainSet::new();
+            set.set_incremental_resize(incremental);
+            let mut model = HashSet::new();
+            for (insert, lo, hi) in ops {
+                // Keys sharing the low byte share their home group
+                let k = (lo as u64 & 7) | ((hi as u64) << 20);
+                if insert {
+                    set.insert(k);
+                    model.insert(k);
+                } else if set.remove(&k) != model.remove(&k) {
+                    return false;
+                }
+                if !check(&set, &model) {
+                    return false;
+                }
+            }
+            true
+        }
+
+        fn take_matches_std_fx(ops: Vec<(bool, u64)>) -> bool {
+            let mut set = Set::<u64, BuildHasherDefault<fx::FxHasher>>::new();
+            let mut model = HashSet::new();
+            for (insert, k) in ops {
+                let k = k % 64;
+                if insert {
+                    set.insert(k);
+                    model.insert(k);
+                } else if set.take(&k) != model.take(&k) {
+                    return false;
+                }
+            }
+            check(&set, &model)
+        }
+    }
 }
@@ -0,0 +1,165 @@
+use std::hash::{Hash, Hasher, BuildHasher};
+use std::collections::hash_map::RandomState;
+use std::sync::Mutex;
+use std::fmt::{self, Debug};
+use set::{self, Set, RawEntryMut};
+
+/// The number of hash bits used to pick a shard. We take them from the top of the
+/// hash, the tables inside the shards pick groups with the low bits.
+const SHARD_BITS: u32 = 5;
+const SHARDS: usize = 1 << SHARD_BITS;
+
+/// A key stored in the arena of a shard. It hashes and compares like the key itself.
+struct Interned<K>(*const K);
+
+impl<K> Clone for Interned<K> {
+    fn clone(&self) -> Self {
+        Interned(self.0)
+    }
+}
+
+impl<K> Copy for Interned<K> {}
+
+impl<K: Hash> Hash for Interned<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        unsafe { (*self.0).hash(state) }
+    }
+}
+
+impl<K: PartialEq> PartialEq for Interned<K> {
+    fn eq(&self, other: &Self) -> bool {
+        unsafe { *self.0 == *other.0 }
+    }
+}
+
+impl<K: Eq> Eq for Interned<K> {}
+
+impl<K: Debug> Debug for Interned<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { (*self.0).fmt(f) }
+    }
+}
+
+// This is a shared reference to the key
+unsafe impl<K: Sync> Send for Interned<K> {}
+
+/// Owns the interned keys. A chunk is never filled past its initial capacity,
+/// so the keys don't move when more are added.
+struct Arena<K> {
+    chunks: Vec<Vec<K>>,
+}
+
+impl<K> Arena<K> {
+    fn alloc(&mut self, k: K) -> *const K {
+        let full = self.chunks.last().map_or(true, |chunk| chunk.len() == chunk.capacity());
+        if full {
+            let capacity = self.chunks.last().map_or(64, |chunk| chunk.capacity() * 2);
+            self.chunks.push(Vec::with_capacity(capacity));
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        chunk.push(k);
+        chunk.last().unwrap()
+    }
+}
+
+struct ShardData<K: Eq + Hash, S> {
+    set: Set<Interned<K>, S>,
+    arena: Arena<K>,
+}
+
+/// Shards are aligned to cache lines, so threads locking different shards don't contend.
+#[repr(align(64))]
+struct Shard<K: Eq + Hash, S>(Mutex<ShardData<K, S>>);
+
+/// A set for interning keys from many threads at once.
+///
+/// Keys are spread over a number of shards based on their hash, each with its own
+/// `Set` and lock, so threads only wait on each other when they hit the same shard.
+/// Interned keys are never removed and don't move, so `intern` can hand out
+/// references which live as long as the set.
+pub struct ShardedSet<K: Eq + Hash, S = RandomState> {
+    hash_builder: S,
+    shards: Vec<Shard<K, S>>,
+}
+
+impl<K: Eq + Hash + Debug, S: BuildHasher + Clone + Default> ShardedSet<K, S> {
+    pub fn new() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K: Eq + Hash + Debug, S: BuildHasher + Clone> ShardedSet<K, S> {
+    /// Creates a set which uses `hash_builder` both to pick shards and in the
+    /// tables of the shards.
+    pub fn with_hasher(hash_builder: S) -> Self {
+        let shards = (0..SHARDS).map(|_| {
+            Shard(Mutex::new(ShardData {
+                set: Set::with_hasher(hash_builder.clone()),
+                arena: Arena { chunks: Vec::new() },
+            }))
+        }).collect();
+        ShardedSet {
+            hash_builder,
+            shards,
+        }
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.0.lock().unwrap().set.len()).sum()
+    }
+
+    /// Returns the key in the set equal to `k`, inserting `k` if there is none.
+    pub fn intern(&self, k: K) -> &K {
+        let hash = set::make_hash(&self.hash_builder, &k);
+        let shard = &self.shards[(hash >> (64 - SHARD_BITS)) as usize];
+        let mut data = shard.0.lock().unwrap();
+        let data = &mut *data;
+        let key = match data.set.raw_entry_mut().from_hash(hash, |key| unsafe { *key.0 == k }) {
+            RawEntryMut::Occupied(e) => *e.into_key(),
+            RawEntryMut::Vacant(e) => {
+                let key = Interned(data.arena.alloc(k));
+                *e.insert_hashed_nocheck(hash, key)
+            }
+        };
+        // The key stays in the arena until the set is dropped
+        unsafe { &*key.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::hash::BuildHasherDefault;
+    use fx;
+
+    #[test]
+    fn intern_from_threads() {
+        let set = Arc::new(ShardedSet::<String, BuildHasherDefault<fx::FxHasher>>::new());
+        let threads: Vec<_> = (0..4).map(|t| {
+            let set = set.clone();
+            thread::spawn(move || {
+                // The threads intern overlapping ranges of strings
+                (t * 500..t * 500 + 1000).map(|i| {
+                    set.intern(format!("sym{}", i)) as *const String as usize
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        let keys: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(set.len(), 2500);
+        for t in 1..4 {
+            // Both threads got the same key for the strings they share
+            assert_eq!(keys[t - 1][500..], keys[t][..500]);
+        }
+        for i in 0..2500 {
+            let name = format!("sym{}", i);
+            assert_eq!(*set.intern(name.clone()), name);
+        }
+        assert_eq!(set.len(), 2500);
+    }
+}
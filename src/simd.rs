@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The instruction set used to search groups.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    Scalar = 1,
+    Sse2 = 2,
+    /// Adds 64-bit compares, which `Map` needs. `Set` uses its SSE2 code here.
+    Sse41 = 3,
+    Avx2 = 4,
+}
+
+// 0 means we haven't detected the level yet
+static LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns true if the CPU we're running on can use `level`.
+pub fn supported(level: Level) -> bool {
+    match level {
+        Level::Scalar => true,
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse2 => true,
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse41 => is_x86_feature_detected!("sse4.1"),
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => is_x86_feature_detected!("avx2"),
+        #[cfg(not(target_arch = "x86_64"))]
+        _ => false,
+    }
+}
+
+/// The level picked with one of the `force-*` cargo features, so the tests can be run
+/// against each implementation.
+fn forced() -> Option<Level> {
+    if cfg!(feature = "force-scalar") {
+        Some(Level::Scalar)
+    } else if cfg!(feature = "force-sse2") {
+        Some(Level::Sse2)
+    } else if cfg!(feature = "force-sse41") {
+        Some(Level::Sse41)
+    } else if cfg!(feature = "force-avx2") {
+        Some(Level::Avx2)
+    } else {
+        None
+    }
+}
+
+#[inline(never)]
+#[cold]
+fn detect() -> Level {
+    let level = if let Some(level) = forced() {
+        assert!(supported(level), "{:?} is not supported by this CPU", level);
+        level
+    } else if supported(Level::Avx2) {
+        Level::Avx2
+    } else if supported(Level::Sse41) {
+        Level::Sse41
+    } else if supported(Level::Sse2) {
+        Level::Sse2
+    } else {
+        Level::Scalar
+    };
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    level
+}
+
+/// The level used by the tables. It is detected the first time it's needed
+/// and then stays the same for the rest of the process, unless `force` is called.
+/// The `force-*` cargo features replace the detection.
+#[inline(always)]
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Scalar,
+        2 => Level::Sse2,
+        3 => Level::Sse41,
+        4 => Level::Avx2,
+        _ => detect(),
+    }
+}
+
+/// Overrides the detected level, so benchmarks and tests can compare the
+/// different implementations. Panics if the CPU doesn't support `level`.
+pub fn force(level: Level) {
+    assert!(supported(level), "{:?} is not supported by this CPU", level);
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+
+/// How the entries of a table are spread over its groups, returned by `Set::stats`
+/// and `Map::stats`. Used to tune the size of groups and the load factor.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The number of entries.
+    pub len: usize,
+    /// The number of entries which fit before the table grows.
+    pub capacity: usize,
+    /// The number of groups. During an incremental resize this includes the old table.
+    pub groups: usize,
+    pub entries_per_group: usize,
+    /// `len` divided by the number of slots in the groups.
+    pub load_factor: f64,
+    /// The bytes allocated for groups.
+    pub memory: usize,
+    /// `probe_lengths[d]` is the number of entries stored `d` groups past their home group.
+    pub probe_lengths: Vec<usize>,
+    /// `group_sizes[n]` is the number of groups holding `n` entries.
+    pub group_sizes: Vec<usize>,
+    /// The number of pairs of entries in the same table with equal stored hashes.
+    /// A lookup of one key of such a pair may have to compare it with the other.
+    pub tag_collisions: usize,
+    /// The number of such pairs we'd expect if the hashes were random.
+    pub expected_tag_collisions: f64,
+}
+
+impl Stats {
+    pub fn new(entries_per_group: usize) -> Stats {
+        Stats {
+            entries_per_group,
+            group_sizes: vec![0; entries_per_group + 1],
+            ..Stats::default()
+        }
+    }
+
+    pub fn add_probe(&mut self, distance: usize) {
+        if self.probe_lengths.len() <= distance {
+            self.probe_lengths.resize(distance + 1, 0);
+        }
+        self.probe_lengths[distance] += 1;
+    }
+
+    /// Fills in the fields computed from the others.
+    pub fn finish(&mut self) {
+        let slots = self.groups * self.entries_per_group;
+        self.load_factor = if slots == 0 { 0.0 } else { self.len as f64 / slots as f64 };
+    }
+
+    /// The average number of groups lookups of the keys in the table skip over.
+    pub fn mean_probe_length(&self) -> f64 {
+        let total: usize = self.probe_lengths.iter().enumerate().map(|(d, &n)| d * n).sum();
+        if self.len == 0 { 0.0 } else { total as f64 / self.len as f64 }
+    }
+}
+
+/// Counts the pairs of equal values in `hashes`, which is sorted.
+pub fn equal_pairs(hashes: &[u64]) -> usize {
+    let mut pairs = 0;
+    let mut run = 0;
+    for i in 1..hashes.len() {
+        if hashes[i] == hashes[i - 1] {
+            run += 1;
+            pairs += run;
+        } else {
+            run = 0;
+        }
+    }
+    pairs
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "entries {} of capacity {} in {} groups of {}",
+                 self.len, self.capacity, self.groups, self.entries_per_group)?;
+        writeln!(f, "load factor {:.3}, {} bytes", self.load_factor, self.memory)?;
+        writeln!(f, "mean probe length {:.3} groups", self.mean_probe_length())?;
+        for (d, n) in self.probe_lengths.iter().enumerate() {
+            writeln!(f, "  probe length {}: {}", d, n)?;
+        }
+        for (size, n) in self.group_sizes.iter().enumerate() {
+            writeln!(f, "  groups with {} entries: {}", size, n)?;
+        }
+        write!(f, "tag collisions {} (expected {:.3})", self.tag_collisions, self.expected_tag_collisions)
+    }
+}
@@ -0,0 +1,352 @@
+use std::marker::PhantomData;
+use std::ptr::{self, Unique, NonNull};
+use std::alloc::Alloc;
+use error::{self, CollectionAllocErr};
+use policy::Policy;
+
+#[cfg(test)]
+thread_local! {
+    /// The number of probe sequences started on this thread, so tests can check how
+    /// often an operation searches a table.
+    pub static PROBES: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+}
+
+/// Groups are allocated as arrays of cache lines, so they are always 64-byte aligned.
+#[repr(align(64), C)]
+pub struct CacheLine([u8; 64]);
+
+/// The layout of a group of entries, which `Table` is generic over.
+///
+/// Lookups start at the group picked by the low bits of the hash and move on to the
+/// next group until one holds the entry or has an empty slot, see `Table::probe`. How
+/// a group finds an entry is up to its user, which can pick SIMD instructions for the
+/// whole lookup. Another layout, say 4 byte keys and values, is just another
+/// implementation of this trait.
+pub trait Group: Sized {
+    /// What is stored for an entry next to its tag.
+    type Entry;
+    /// What is stored to tell entries apart in lookups. Sets store part of the hash,
+    /// maps with 8 byte keys store the keys themselves.
+    type Tag: Copy;
+
+    /// The number of entries in a group.
+    fn entries() -> usize;
+
+    /// The size of a group in bytes. This has to be a multiple of 64.
+    fn stride() -> usize;
+
+    /// Empties the group. New groups are initialized with this.
+    fn clear(&mut self);
+
+    /// Returns the position of an empty slot, if there is one.
+    fn search_for_empty(&self) -> Option<usize>;
+
+    /// Writes an entry into the slot at `pos`. Groups which keep a count of their
+    /// entries leave updating it to the caller.
+    fn set(&mut self, pos: usize, tag: Self::Tag, entry: Self::Entry);
+
+    /// Calls `f` with the position of each entry in the group.
+    fn iter<F: FnMut(usize)>(&self, f: F);
+}
+
+/// An array of `G`s with linear probing between groups. `Set` and `Map` build on this
+/// and add removal and growing, which depend on the layout of their groups.
+pub struct Table<G: Group, A: Alloc> {
+    pub group_mask: usize,
+    pub size: usize,
+    pub capacity: usize,
+    pub groups: Unique<CacheLine>,
+    pub alloc: A,
+    pub marker: PhantomData<G>,
+}
+
+#[derive(Debug)]
+pub struct RawEntry<G> {
+    pub group: *mut G,
+    pub pos: usize,
+    pub empty: bool
+}
+
+impl<G: Group, A: Alloc> Table<G, A> {
+    /// Allocates `group_count` empty groups.
+    /// Returns an error if it cannot allocate or capacity overflows.
+    pub fn new(group_count: usize, mut alloc: A, policy: &Policy) -> Result<Table<G, A>, CollectionAllocErr> {
+        let lines = Self::lines(group_count)?;
+        let groups: NonNull<CacheLine> = alloc.alloc_array(lines)?;
+
+        let mut table = Table {
+            group_mask: group_count.wrapping_sub(1),
+            size: 0,
+            capacity: Self::capacity(group_count, policy),
+            groups: unsafe { Unique::new_unchecked(groups.as_ptr()) },
+            alloc,
+            marker: PhantomData,
+        };
+        table.clear();
+        Ok(table)
+    }
+
+    /// The number of cache lines taken up by `group_count` groups. Fails if their
+    /// size in bytes doesn't fit in an `isize`, which allocators can't handle.
+    pub fn lines(group_count: usize) -> Result<usize, CollectionAllocErr> {
+        match group_count.checked_mul(G::stride() / 64) {
+            Some(lines) if lines <= isize::max_value() as usize / 64 => Ok(lines),
+            _ => Err(CollectionAllocErr::CapacityOverflow),
+        }
+    }
+
+    /// The smallest number of groups which can hold `capacity` entries without growing.
+    pub fn group_count_for(capacity: usize, policy: &Policy) -> Result<usize, CollectionAllocErr> {
+        let mut group_count = 1;
+        while Self::capacity(group_count, policy) < capacity {
+            group_count = group_count.checked_mul(2).ok_or(CollectionAllocErr::CapacityOverflow)?;
+            Self::lines(group_count)?;
+        }
+        Ok(group_count)
+    }
+
+    /// The number of entries we allow in a table with `group_count` groups before it grows.
+    pub fn capacity(group_count: usize, policy: &Policy) -> usize {
+        policy.capacity(group_count * G::entries())
+    }
+
+    #[inline(always)]
+    pub fn group(&self, group_idx: usize) -> *mut G {
+        unsafe {
+            (self.groups.as_ptr() as *mut u8)
+                .offset((group_idx * G::stride()) as isize) as *mut G
+        }
+    }
+
+    /// Calls `search` on the groups starting from the home group of `hash`
+    /// until it returns a slot.
+    #[inline(always)]
+    pub fn probe<F: FnMut(&G) -> Option<(usize, bool)>>(&self, hash: u64, mut search: F) -> RawEntry<G> {
+        #[cfg(test)]
+        PROBES.with(|probes| probes.set(probes.get() + 1));
+        let mask = self.group_mask;
+        let mut group_idx = hash as usize & mask;
+
+        loop {
+            let group_ptr = self.group(group_idx);
+            let group = unsafe {
+                &(*group_ptr)
+            };
+            match search(group) {
+                Some((pos, empty)) => return RawEntry {
+                    group: group_ptr,
+                    pos,
+                    empty,
+                },
+                None => (),
+            }
+            group_idx = (group_idx + 1) & mask;
+        }
+    }
+
+    #[inline(always)]
+    pub fn search_for_empty(&self, hash: u64) -> RawEntry<G> {
+        self.probe(hash, |group| group.search_for_empty().map(|pos| (pos, true)))
+    }
+
+    /// Calls `f` with each group and the position of each entry in it.
+    pub fn iter<F: FnMut(&G, usize)>(&self, mut f: F) {
+        for i in 0..(self.group_mask + 1) {
+            let group = unsafe {
+                &(*self.group(i))
+            };
+            group.iter(|pos| f(group, pos));
+        }
+    }
+
+    /// Checks that lookups find every entry, which holds if all groups between an entry's
+    /// home group and its own group are full, and that `size` counts the entries.
+    /// `hash` returns the hash of the entry at a position in a group.
+    pub fn validate<F: FnMut(&G, usize) -> u64>(&self, mut hash: F) -> Result<(), String> {
+        let mask = self.group_mask;
+        let mut entries = 0;
+        for i in 0..(mask + 1) {
+            let group = unsafe {
+                &(*self.group(i))
+            };
+            let mut error = None;
+            group.iter(|pos| {
+                entries += 1;
+                let home = hash(group, pos) as usize & mask;
+                let mut idx = home;
+                while idx != i && error.is_none() {
+                    if unsafe { (*self.group(idx)).search_for_empty() }.is_some() {
+                        error = Some(format!("group {}: the entry at {} has home group {}, but group {} \
+                                              before it isn't full", i, pos, home, idx));
+                    }
+                    idx = (idx + 1) & mask;
+                }
+            });
+            if let Some(error) = error {
+                return Err(error);
+            }
+        }
+        if entries != self.size {
+            return Err(format!("the size is {}, but the groups hold {} entries", self.size, entries));
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        for i in 0..(self.group_mask + 1) {
+            let group = unsafe {
+                &mut (*self.group(i))
+            };
+            group.clear();
+        }
+        self.size = 0;
+    }
+}
+
+impl<G: Group, A: Alloc + Clone> Table<G, A> {
+    /// Copies the groups as they are and then calls `clone` with each entry's group,
+    /// the same group in the copy and the entry's position, so it can replace the
+    /// copied bits of the entry with a clone. The table doesn't drop its entries, so
+    /// if `clone` panics the copy is just freed.
+    pub fn clone_with<F: FnMut(&G, &mut G, usize)>(&self, mut clone: F) -> Self {
+        let lines = (self.group_mask + 1) * (G::stride() / 64);
+        unsafe {
+            let mut alloc = self.alloc.clone();
+            let groups: NonNull<CacheLine> = error::unwrap(alloc.alloc_array(lines).map_err(CollectionAllocErr::from));
+            ptr::copy_nonoverlapping(self.groups.as_ptr(), groups.as_ptr(), lines);
+            let table = Table {
+                group_mask: self.group_mask,
+                size: self.size,
+                capacity: self.capacity,
+                groups: Unique::new_unchecked(groups.as_ptr()),
+                alloc,
+                marker: PhantomData,
+            };
+            for i in 0..(self.group_mask + 1) {
+                let from = &*self.group(i);
+                let to = &mut *table.group(i);
+                from.iter(|pos| clone(from, to, pos));
+            }
+            table
+        }
+    }
+}
+
+impl<G: Group, A: Alloc + Clone> Clone for Table<G, A> where G::Entry: Copy {
+    /// The entries are `Copy`, so copying the groups as they are is all there is to do.
+    fn clone(&self) -> Self {
+        self.clone_with(|_, _, _| ())
+    }
+}
+
+impl<G: Group, A: Alloc> Drop for Table<G, A> {
+    fn drop(&mut self) {
+        unsafe {
+            self.alloc.dealloc_array(
+                NonNull::new_unchecked(self.groups.as_ptr()),
+                (self.group_mask + 1) * (G::stride() / 64)
+            ).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::Global;
+
+    /// 4 byte keys and values, with 0 for empty slots.
+    #[repr(align(64), C)]
+    struct Pairs {
+        keys: [u32; 8],
+        values: [u32; 8],
+    }
+
+    impl Group for Pairs {
+        type Entry = u32;
+        type Tag = u32;
+
+        fn entries() -> usize {
+            8
+        }
+
+        fn stride() -> usize {
+            64
+        }
+
+        fn clear(&mut self) {
+            self.keys = [0; 8];
+        }
+
+        fn search_for_empty(&self) -> Option<usize> {
+            self.keys.iter().position(|&k| k == 0)
+        }
+
+        fn set(&mut self, pos: usize, key: u32, value: u32) {
+            self.keys[pos] = key;
+            self.values[pos] = value;
+        }
+
+        fn iter<F: FnMut(usize)>(&self, mut f: F) {
+            for pos in 0..8 {
+                if self.keys[pos] != 0 {
+                    f(pos);
+                }
+            }
+        }
+    }
+
+    impl Pairs {
+        fn search(&self, key: u32) -> Option<(usize, bool)> {
+            match self.keys.iter().position(|&k| k == key) {
+                Some(pos) => Some((pos, false)),
+                None => self.search_for_empty().map(|pos| (pos, true)),
+            }
+        }
+    }
+
+    fn insert(table: &mut Table<Pairs, Global>, key: u32, value: u32) {
+        let spot = table.probe(key as u64, |group| group.search(key));
+        if spot.empty {
+            table.size += 1;
+        }
+        unsafe {
+            (*spot.group).set(spot.pos, key, value);
+        }
+    }
+
+    fn get(table: &Table<Pairs, Global>, key: u32) -> Option<u32> {
+        let spot = table.probe(key as u64, |group| group.search(key));
+        if spot.empty {
+            None
+        } else {
+            Some(unsafe { (*spot.group).values[spot.pos] })
+        }
+    }
+
+    #[test]
+    fn custom_group() {
+        let policy = Policy::default();
+        let groups = Table::<Pairs, Global>::group_count_for(100, &policy).unwrap();
+        let mut table = Table::<Pairs, Global>::new(groups, Global, &policy).unwrap();
+        assert!(table.capacity >= 100);
+        for key in 1..101 {
+            insert(&mut table, key, key * 2);
+        }
+        insert(&mut table, 7, 0);
+        assert_eq!(table.size, 100);
+        assert_eq!(get(&table, 7), Some(0));
+        assert_eq!(get(&table, 8), Some(16));
+        assert_eq!(get(&table, 101), None);
+
+        let copy = table.clone();
+        let mut sum = 0;
+        copy.iter(|group, pos| sum += group.keys[pos]);
+        assert_eq!(sum, 5050);
+
+        table.clear();
+        assert_eq!(table.size, 0);
+        assert_eq!(get(&table, 8), None);
+        assert_eq!(get(&copy, 8), Some(16));
+    }
+}